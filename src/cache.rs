@@ -0,0 +1,561 @@
+//! An in-memory cache of the answers we got back from the upstream resolver,
+//! including negative answers (RFC 2308).
+
+use std::collections::{BTreeMap, HashMap};
+use std::time::{Duration, Instant};
+
+use crate::name::DnsName;
+use crate::rdata::RData;
+use crate::structs::{DnsAnswer, DnsMessage, DnsQuestion, RCODE_NOERROR, RCODE_NXDOMAIN};
+
+/// Rough per-entry cost of the map slots and bookkeeping, on top of the records.
+const ENTRY_OVERHEAD: usize = 64;
+
+/// The bits of a query that change what the upstream sends back: with DO set
+/// answers come with their RRSIGs, and with CD set they aren't validated. An
+/// answer is only served to queries that asked the same way.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Hash)]
+pub struct QueryFlags {
+    pub dnssec_ok: bool,
+    pub checking_disabled: bool,
+}
+
+impl QueryFlags {
+    pub fn of(query: &DnsMessage) -> QueryFlags {
+        QueryFlags {
+            dnssec_ok: (query.edns.as_ref()).is_some_and(|edns| edns.dnssec_ok),
+            checking_disabled: query.header.checking_disabled,
+        }
+    }
+}
+
+#[derive(Debug, Clone, PartialEq, Eq, Hash)]
+struct CacheKey {
+    name: DnsName,
+    qtype: u16,
+    qclass: u16,
+    flags: QueryFlags,
+}
+
+impl CacheKey {
+    fn new(question: &DnsQuestion, flags: QueryFlags) -> CacheKey {
+        CacheKey {
+            name: question.qname.clone(),
+            qtype: question.qtype,
+            qclass: question.qclass,
+            flags,
+        }
+    }
+}
+
+/// What we know about a question.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum CachedAnswer {
+    /// The records answering it.
+    Records(Vec<DnsAnswer>),
+    /// NXDOMAIN, or NODATA when `rcode` is NOERROR: nothing to answer with, and
+    /// the zone's SOA to put in the authority section. The SOA's TTL is the
+    /// negative TTL rather than its own (RFC 2308 3).
+    Negative { rcode: u16, soa: DnsAnswer },
+}
+
+impl CachedAnswer {
+    /// What's worth caching from an upstream response to a single question, if
+    /// anything. Negative answers without an SOA in the authority section can't
+    /// be cached since there's nothing to say how long for (RFC 2308 5).
+    pub fn from_response(response: &DnsMessage) -> Option<CachedAnswer> {
+        if response.header.truncated_message {
+            return None;
+        }
+        let rcode = response.rcode();
+        match rcode {
+            RCODE_NOERROR if !response.answers.is_empty() => {
+                Some(CachedAnswer::Records(response.answers.clone()))
+            }
+            // an NXDOMAIN at the end of a CNAME chain would need the chain kept
+            // too, so only plain ones are cached
+            RCODE_NOERROR | RCODE_NXDOMAIN if response.answers.is_empty() => {
+                let soa = response
+                    .authorities
+                    .iter()
+                    .find_map(|record| match record.rdata {
+                        RData::Soa { minimum, .. } => Some(DnsAnswer {
+                            ttl: record.ttl.min(minimum),
+                            ..record.clone()
+                        }),
+                        _ => None,
+                    })?;
+                Some(CachedAnswer::Negative { rcode, soa })
+            }
+            _ => None,
+        }
+    }
+
+    /// How long the answer can be kept: the shortest TTL among the records.
+    fn ttl(&self) -> Option<u32> {
+        match self {
+            CachedAnswer::Records(records) => records.iter().map(|record| record.ttl).min(),
+            CachedAnswer::Negative { soa, .. } => Some(soa.ttl),
+        }
+    }
+
+    fn records(&self) -> &[DnsAnswer] {
+        match self {
+            CachedAnswer::Records(records) => records,
+            CachedAnswer::Negative { soa, .. } => std::slice::from_ref(soa),
+        }
+    }
+
+    fn with_ttls_reduced_by(&self, elapsed: u32) -> CachedAnswer {
+        let reduce = |record: &DnsAnswer| DnsAnswer {
+            ttl: record.ttl.saturating_sub(elapsed),
+            ..record.clone()
+        };
+        match self {
+            CachedAnswer::Records(records) => {
+                CachedAnswer::Records(records.iter().map(reduce).collect())
+            }
+            CachedAnswer::Negative { rcode, soa } => CachedAnswer::Negative {
+                rcode: *rcode,
+                soa: reduce(soa),
+            },
+        }
+    }
+}
+
+#[derive(Debug)]
+struct CacheEntry {
+    answer: CachedAnswer,
+    inserted: Instant,
+    // when the shortest lived record runs out
+    expires: Instant,
+    last_used: u64,
+    size: usize,
+}
+
+/// Answers keyed by the question they answer. Each entry lives as long as its
+/// own shortest TTL, positive or negative, and once the estimated size passes
+/// `max_size` the least recently used entries are dropped to make room.
+#[derive(Debug)]
+pub struct Cache {
+    entries: HashMap<CacheKey, CacheEntry>,
+    // last_used tick -> key, oldest first
+    lru: BTreeMap<u64, CacheKey>,
+    tick: u64,
+    used_size: usize,
+    max_size: usize,
+}
+
+impl Cache {
+    /// A cache holding roughly `max_size` bytes of records. Zero turns it off.
+    pub fn new(max_size: usize) -> Cache {
+        Cache {
+            entries: HashMap::new(),
+            lru: BTreeMap::new(),
+            tick: 0,
+            used_size: 0,
+            max_size,
+        }
+    }
+
+    pub fn len(&self) -> usize {
+        self.entries.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.entries.is_empty()
+    }
+
+    /// Estimated bytes held, as counted against the cap.
+    pub fn used_size(&self) -> usize {
+        self.used_size
+    }
+
+    /// The cached answer to `question` asked with `flags`, with each TTL reduced
+    /// by the time it has spent in the cache.
+    pub fn get(
+        &mut self,
+        question: &DnsQuestion,
+        flags: QueryFlags,
+        now: Instant,
+    ) -> Option<CachedAnswer> {
+        let key = CacheKey::new(question, flags);
+        let entry = self.entries.get(&key)?;
+        if now >= entry.expires {
+            self.remove(&key);
+            return None;
+        }
+        let elapsed = now.duration_since(entry.inserted).as_secs();
+        let elapsed = u32::try_from(elapsed).unwrap_or(u32::MAX);
+        let answer = entry.answer.with_ttls_reduced_by(elapsed);
+        self.touch(&key);
+        Some(answer)
+    }
+
+    /// Caches `answer` for `question` asked with `flags`, replacing anything
+    /// already there. Empty answers and anything with a zero TTL aren't cached.
+    pub fn insert(
+        &mut self,
+        question: &DnsQuestion,
+        flags: QueryFlags,
+        answer: CachedAnswer,
+        now: Instant,
+    ) {
+        let Some(ttl) = answer.ttl() else {
+            return;
+        };
+        if ttl == 0 {
+            return;
+        }
+        let key = CacheKey::new(question, flags);
+        self.remove(&key);
+        let size = entry_size(&key, answer.records());
+        if size > self.max_size {
+            return;
+        }
+        if self.used_size + size > self.max_size {
+            self.remove_expired(now);
+        }
+        while self.used_size + size > self.max_size {
+            let (_, oldest) = self
+                .lru
+                .pop_first()
+                .expect("used size is counted in entries");
+            let entry = self.entries.remove(&oldest).expect("lru and entries agree");
+            self.used_size -= entry.size;
+        }
+        self.tick += 1;
+        self.lru.insert(self.tick, key.clone());
+        self.used_size += size;
+        self.entries.insert(
+            key,
+            CacheEntry {
+                answer,
+                inserted: now,
+                expires: now + Duration::from_secs(ttl as u64),
+                last_used: self.tick,
+                size,
+            },
+        );
+    }
+
+    fn touch(&mut self, key: &CacheKey) {
+        if let Some(entry) = self.entries.get_mut(key) {
+            self.lru.remove(&entry.last_used);
+            self.tick += 1;
+            entry.last_used = self.tick;
+            self.lru.insert(self.tick, key.clone());
+        }
+    }
+
+    fn remove(&mut self, key: &CacheKey) {
+        if let Some(entry) = self.entries.remove(key) {
+            self.lru.remove(&entry.last_used);
+            self.used_size -= entry.size;
+        }
+    }
+
+    fn remove_expired(&mut self, now: Instant) {
+        let expired: Vec<CacheKey> = self
+            .entries
+            .iter()
+            .filter(|(_, entry)| now >= entry.expires)
+            .map(|(key, _)| key.clone())
+            .collect();
+        for key in expired {
+            self.remove(&key);
+        }
+    }
+}
+
+/// About what an entry costs to keep: the records in uncompressed wire form
+/// plus the key and some overhead.
+fn entry_size(key: &CacheKey, records: &[DnsAnswer]) -> usize {
+    let records_size: usize = records
+        .iter()
+        .map(|record| record.name.wire_len() + 10 + record.rdata.to_bytes().len())
+        .sum();
+    ENTRY_OVERHEAD + key.name.wire_len() + 4 + records_size
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::rdata::{TYPE_A, TYPE_AAAA};
+    use std::net::Ipv4Addr;
+
+    const PLAIN: QueryFlags = QueryFlags {
+        dnssec_ok: false,
+        checking_disabled: false,
+    };
+
+    fn question(s: &str) -> DnsQuestion {
+        DnsQuestion::new(s.parse().unwrap(), TYPE_A)
+    }
+
+    fn a_record(s: &str, ttl: u32) -> DnsAnswer {
+        DnsAnswer::new(
+            s.parse().unwrap(),
+            ttl,
+            RData::A(Ipv4Addr::new(192, 0, 2, 1)),
+        )
+    }
+
+    #[test]
+    fn test_cache_hit_decrements_ttl() {
+        let mut cache = Cache::new(4096);
+        let now = Instant::now();
+        cache.insert(
+            &question("example.com"),
+            PLAIN,
+            CachedAnswer::Records(vec![
+                a_record("example.com", 300),
+                a_record("example.com", 60),
+            ]),
+            now,
+        );
+        // the key ignores case like the names themselves do
+        let answer = cache.get(
+            &question("EXAMPLE.com"),
+            PLAIN,
+            now + Duration::from_secs(25),
+        );
+        let Some(CachedAnswer::Records(records)) = answer else {
+            panic!("expected records, got {:?}", answer);
+        };
+        assert_eq!(records[0].ttl, 275);
+        assert_eq!(records[1].ttl, 35);
+        assert!(cache.get(&question("other.com"), PLAIN, now).is_none());
+    }
+
+    #[test]
+    fn test_cache_keeps_dnssec_answers_apart() {
+        let mut cache = Cache::new(4096);
+        let now = Instant::now();
+        let dnssec = QueryFlags {
+            dnssec_ok: true,
+            ..PLAIN
+        };
+        let unchecked = QueryFlags {
+            checking_disabled: true,
+            ..PLAIN
+        };
+        let records = CachedAnswer::Records(vec![a_record("example.com", 300)]);
+        cache.insert(&question("example.com"), dnssec, records, now);
+        assert!(cache.get(&question("example.com"), dnssec, now).is_some());
+        assert!(cache.get(&question("example.com"), PLAIN, now).is_none());
+        assert!(cache
+            .get(&question("example.com"), unchecked, now)
+            .is_none());
+    }
+
+    #[test]
+    fn test_cache_expires_with_shortest_ttl() {
+        let mut cache = Cache::new(4096);
+        let now = Instant::now();
+        cache.insert(
+            &question("example.com"),
+            PLAIN,
+            CachedAnswer::Records(vec![
+                a_record("example.com", 300),
+                a_record("example.com", 60),
+            ]),
+            now,
+        );
+        assert!(cache
+            .get(
+                &question("example.com"),
+                PLAIN,
+                now + Duration::from_secs(59)
+            )
+            .is_some());
+        assert!(cache
+            .get(
+                &question("example.com"),
+                PLAIN,
+                now + Duration::from_secs(60)
+            )
+            .is_none());
+        assert!(cache.is_empty());
+        assert_eq!(cache.used_size(), 0);
+    }
+
+    #[test]
+    fn test_cache_skips_uncacheable_answers() {
+        let mut cache = Cache::new(4096);
+        let now = Instant::now();
+        cache.insert(
+            &question("example.com"),
+            PLAIN,
+            CachedAnswer::Records(Vec::new()),
+            now,
+        );
+        cache.insert(
+            &question("example.org"),
+            PLAIN,
+            CachedAnswer::Records(vec![a_record("example.org", 0)]),
+            now,
+        );
+        assert!(cache.is_empty());
+        // too big to ever fit
+        let mut tiny = Cache::new(16);
+        tiny.insert(
+            &question("example.com"),
+            PLAIN,
+            CachedAnswer::Records(vec![a_record("example.com", 60)]),
+            now,
+        );
+        assert!(tiny.is_empty());
+    }
+
+    #[test]
+    fn test_cache_evicts_least_recently_used() {
+        let now = Instant::now();
+        let size = entry_size(
+            &CacheKey::new(&question("a.example"), PLAIN),
+            &[a_record("a.example", 60)],
+        );
+        let mut cache = Cache::new(size * 2);
+        cache.insert(
+            &question("a.example"),
+            PLAIN,
+            CachedAnswer::Records(vec![a_record("a.example", 60)]),
+            now,
+        );
+        cache.insert(
+            &question("b.example"),
+            PLAIN,
+            CachedAnswer::Records(vec![a_record("b.example", 60)]),
+            now,
+        );
+        // using a makes b the oldest
+        assert!(cache.get(&question("a.example"), PLAIN, now).is_some());
+        cache.insert(
+            &question("c.example"),
+            PLAIN,
+            CachedAnswer::Records(vec![a_record("c.example", 60)]),
+            now,
+        );
+        assert_eq!(cache.len(), 2);
+        assert!(cache.get(&question("b.example"), PLAIN, now).is_none());
+        assert!(cache.get(&question("a.example"), PLAIN, now).is_some());
+        assert!(cache.get(&question("c.example"), PLAIN, now).is_some());
+    }
+
+    #[test]
+    fn test_cache_evicts_expired_before_live() {
+        let now = Instant::now();
+        let size = entry_size(
+            &CacheKey::new(&question("a.example"), PLAIN),
+            &[a_record("a.example", 60)],
+        );
+        let mut cache = Cache::new(size * 2);
+        cache.insert(
+            &question("a.example"),
+            PLAIN,
+            CachedAnswer::Records(vec![a_record("a.example", 600)]),
+            now,
+        );
+        cache.insert(
+            &question("b.example"),
+            PLAIN,
+            CachedAnswer::Records(vec![a_record("b.example", 10)]),
+            now,
+        );
+        assert!(cache.get(&question("b.example"), PLAIN, now).is_some());
+        // a is the least recently used but b has expired, so b goes
+        let later = now + Duration::from_secs(20);
+        cache.insert(
+            &question("c.example"),
+            PLAIN,
+            CachedAnswer::Records(vec![a_record("c.example", 60)]),
+            later,
+        );
+        assert!(cache.get(&question("a.example"), PLAIN, later).is_some());
+        assert!(cache.get(&question("c.example"), PLAIN, later).is_some());
+    }
+
+    fn soa_record(ttl: u32, minimum: u32) -> DnsAnswer {
+        DnsAnswer::new(
+            "example.com".parse().unwrap(),
+            ttl,
+            RData::Soa {
+                mname: "ns1.example.com".parse().unwrap(),
+                rname: "hostmaster.example.com".parse().unwrap(),
+                serial: 1,
+                refresh: 7200,
+                retry: 3600,
+                expire: 1209600,
+                minimum,
+            },
+        )
+    }
+
+    fn negative_response(rcode: u16, authorities: Vec<DnsAnswer>) -> DnsMessage {
+        let mut response =
+            DnsMessage::reply_to(&DnsMessage::query(1, question("missing.example.com")));
+        response.set_rcode(rcode);
+        response.authorities = authorities;
+        response
+    }
+
+    #[test]
+    fn test_cached_answer_from_negative_response() {
+        // the negative TTL is the lesser of the SOA's own TTL and its minimum
+        let nxdomain = negative_response(RCODE_NXDOMAIN, vec![soa_record(3600, 300)]);
+        assert_eq!(
+            CachedAnswer::from_response(&nxdomain),
+            Some(CachedAnswer::Negative {
+                rcode: RCODE_NXDOMAIN,
+                soa: soa_record(300, 300),
+            })
+        );
+        let nodata = negative_response(RCODE_NOERROR, vec![soa_record(60, 300)]);
+        assert_eq!(
+            CachedAnswer::from_response(&nodata),
+            Some(CachedAnswer::Negative {
+                rcode: RCODE_NOERROR,
+                soa: soa_record(60, 300),
+            })
+        );
+        // no SOA, no idea how long it's good for
+        assert_eq!(
+            CachedAnswer::from_response(&negative_response(RCODE_NXDOMAIN, Vec::new())),
+            None
+        );
+        let servfail = negative_response(2, vec![soa_record(3600, 300)]);
+        assert_eq!(CachedAnswer::from_response(&servfail), None);
+    }
+
+    #[test]
+    fn test_cache_negative_answers_expire_independently() {
+        let mut cache = Cache::new(4096);
+        let now = Instant::now();
+        cache.insert(
+            &question("example.com"),
+            PLAIN,
+            CachedAnswer::Records(vec![a_record("example.com", 600)]),
+            now,
+        );
+        // NODATA for AAAA at the same name
+        let aaaa = DnsQuestion::new("example.com".parse().unwrap(), TYPE_AAAA);
+        cache.insert(
+            &aaaa,
+            PLAIN,
+            CachedAnswer::Negative {
+                rcode: RCODE_NOERROR,
+                soa: soa_record(30, 30),
+            },
+            now,
+        );
+        let answer = cache.get(&aaaa, PLAIN, now + Duration::from_secs(10));
+        let Some(CachedAnswer::Negative { rcode, soa }) = answer else {
+            panic!("expected a negative answer, got {:?}", answer);
+        };
+        assert_eq!(rcode, RCODE_NOERROR);
+        assert_eq!(soa.ttl, 20);
+        let later = now + Duration::from_secs(30);
+        assert!(cache.get(&aaaa, PLAIN, later).is_none());
+        assert!(cache.get(&question("example.com"), PLAIN, later).is_some());
+    }
+}
@@ -0,0 +1,200 @@
+//! The config file. It has one directive per line, and `#` starts a comment:
+//!
+//! ```text
+//! # questions under corp.internal go to the office resolvers
+//! forward corp.internal 10.0.0.53 10.0.0.54
+//! forward consul 127.0.0.1:8600
+//! # and everything else to a public one
+//! forward . 9.9.9.9
+//! # we answer for example.com ourselves, from a master file
+//! zone example.com zones/example.com.zone
+//! ```
+//!
+//! A question goes to the rule with the longest suffix matching its name.
+//! Addresses without a port use 53. Zone file paths are relative to the config
+//! file.
+
+use std::fs;
+use std::net::IpAddr;
+use std::path::{Path, PathBuf};
+use std::str::FromStr;
+
+use crate::error::ConfigError;
+use crate::name::DnsName;
+
+/// Where to send questions for names at or below `suffix`.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct ForwardRule {
+    pub suffix: DnsName,
+    pub upstreams: Vec<String>,
+}
+
+/// A zone to serve with authority, and the master file it's in.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct ZoneFile {
+    pub origin: DnsName,
+    pub path: PathBuf,
+}
+
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct ConfigFile {
+    pub forward_rules: Vec<ForwardRule>,
+    pub zones: Vec<ZoneFile>,
+}
+
+impl ConfigFile {
+    pub fn load(path: &Path) -> Result<ConfigFile, ConfigError> {
+        let text = fs::read_to_string(path).map_err(|source| ConfigError::Io {
+            path: path.display().to_string(),
+            source,
+        })?;
+        let mut config: ConfigFile = text.parse()?;
+        let dir = path.parent().unwrap_or(Path::new("."));
+        for zone in &mut config.zones {
+            zone.path = dir.join(&zone.path);
+        }
+        Ok(config)
+    }
+}
+
+impl FromStr for ConfigFile {
+    type Err = ConfigError;
+
+    fn from_str(text: &str) -> Result<ConfigFile, ConfigError> {
+        let mut config = ConfigFile::default();
+        for (i, line) in text.lines().enumerate() {
+            let syntax = |message: String| ConfigError::Syntax {
+                line: i + 1,
+                message,
+            };
+            let line = line.split('#').next().unwrap_or_default();
+            let mut words = line.split_whitespace();
+            let Some(directive) = words.next() else {
+                continue;
+            };
+            match directive {
+                "forward" => {
+                    let suffix = words
+                        .next()
+                        .ok_or_else(|| syntax("forward needs a domain".to_string()))?;
+                    let suffix: DnsName = suffix
+                        .parse()
+                        .map_err(|e| syntax(format!("bad domain {:?}: {}", suffix, e)))?;
+                    let upstreams: Vec<String> = words.map(with_default_port).collect();
+                    if upstreams.is_empty() {
+                        return Err(syntax(format!("no resolvers given for {}", suffix)));
+                    }
+                    if config
+                        .forward_rules
+                        .iter()
+                        .any(|rule| rule.suffix == suffix)
+                    {
+                        return Err(syntax(format!("{} is already forwarded", suffix)));
+                    }
+                    config.forward_rules.push(ForwardRule { suffix, upstreams });
+                }
+                "zone" => {
+                    let (Some(origin), Some(path), None) =
+                        (words.next(), words.next(), words.next())
+                    else {
+                        return Err(syntax("zone needs a domain and a file".to_string()));
+                    };
+                    let origin: DnsName = origin
+                        .parse()
+                        .map_err(|e| syntax(format!("bad domain {:?}: {}", origin, e)))?;
+                    if config.zones.iter().any(|zone| zone.origin == origin) {
+                        return Err(syntax(format!("zone {} is already configured", origin)));
+                    }
+                    config.zones.push(ZoneFile {
+                        origin,
+                        path: PathBuf::from(path),
+                    });
+                }
+                _ => return Err(syntax(format!("unknown directive {:?}", directive))),
+            }
+        }
+        Ok(config)
+    }
+}
+
+/// Adds port 53 to a bare IP address; anything else is left for the socket
+/// code to make sense of.
+fn with_default_port(address: &str) -> String {
+    match address.parse::<IpAddr>() {
+        Ok(IpAddr::V4(ip)) => format!("{}:53", ip),
+        Ok(IpAddr::V6(ip)) => format!("[{}]:53", ip),
+        Err(_) => address.to_string(),
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn test_config_forward_rules() {
+        let config: ConfigFile = "
+            # office names
+            forward corp.internal 10.0.0.53 10.0.0.54:5353
+            forward consul 127.0.0.1:8600   # local agent
+            forward . 2620:fe::fe
+        "
+        .parse()
+        .unwrap();
+        assert_eq!(
+            config.forward_rules,
+            vec![
+                ForwardRule {
+                    suffix: "corp.internal".parse().unwrap(),
+                    upstreams: vec!["10.0.0.53:53".to_string(), "10.0.0.54:5353".to_string()],
+                },
+                ForwardRule {
+                    suffix: "consul".parse().unwrap(),
+                    upstreams: vec!["127.0.0.1:8600".to_string()],
+                },
+                ForwardRule {
+                    suffix: DnsName::root(),
+                    upstreams: vec!["[2620:fe::fe]:53".to_string()],
+                },
+            ]
+        );
+    }
+
+    #[test]
+    fn test_config_zones() {
+        let config: ConfigFile = "
+            zone example.com zones/example.com.zone
+            zone 2.0.192.in-addr.arpa /var/zones/reverse
+        "
+        .parse()
+        .unwrap();
+        assert_eq!(
+            config.zones,
+            vec![
+                ZoneFile {
+                    origin: "example.com".parse().unwrap(),
+                    path: PathBuf::from("zones/example.com.zone"),
+                },
+                ZoneFile {
+                    origin: "2.0.192.in-addr.arpa".parse().unwrap(),
+                    path: PathBuf::from("/var/zones/reverse"),
+                },
+            ]
+        );
+    }
+
+    #[test]
+    fn test_config_errors_have_line_numbers() {
+        let line_of = |text: &str| match text.parse::<ConfigFile>() {
+            Err(ConfigError::Syntax { line, .. }) => line,
+            other => panic!("expected a syntax error, got {:?}", other),
+        };
+        assert_eq!(line_of("forward a 10.0.0.1\nforward b"), 2);
+        assert_eq!(line_of("\n\nforwrd a 10.0.0.1"), 3);
+        assert_eq!(line_of("forward a..b 10.0.0.1"), 1);
+        assert_eq!(line_of("forward a 10.0.0.1\nforward A. 10.0.0.2"), 2);
+        assert_eq!(line_of("zone a a.zone\nzone b"), 2);
+        assert_eq!(line_of("zone a a.zone extra"), 1);
+        assert_eq!(line_of("zone a a.zone\nzone a. other.zone"), 2);
+    }
+}
@@ -0,0 +1,234 @@
+//! EDNS(0), carried in the OPT pseudo-record (RFC 6891).
+
+use crate::error::{DnsParseError, ParseResult};
+use crate::name::DnsName;
+use crate::rdata::RData;
+use crate::structs::DnsAnswer;
+
+pub const TYPE_OPT: u16 = 41;
+/// What we advertise to clients and upstreams, and so the most we'll ever
+/// receive or send in one datagram.
+pub const SERVER_UDP_PAYLOAD_SIZE: u16 = 4096;
+/// The classic limit for clients that don't do EDNS (RFC 1035 4.2.1).
+pub const CLASSIC_UDP_PAYLOAD_SIZE: u16 = 512;
+
+/// One option from the OPT record's data, left undecoded.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct EdnsOption {
+    pub code: u16,
+    pub data: Vec<u8>,
+}
+
+/// The contents of an OPT pseudo-record (RFC 6891). It travels in the
+/// additional section but isn't really a record, so DnsMessage keeps it apart.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Edns {
+    pub udp_payload_size: u16,
+    pub extended_rcode: u8, // the upper 8 bits of the 12 bit rcode
+    pub version: u8,
+    pub dnssec_ok: bool,
+    pub options: Vec<EdnsOption>,
+}
+
+impl Default for Edns {
+    fn default() -> Self {
+        Edns {
+            udp_payload_size: SERVER_UDP_PAYLOAD_SIZE,
+            extended_rcode: 0,
+            version: 0,
+            dnssec_ok: false,
+            options: Vec::new(),
+        }
+    }
+}
+
+impl Edns {
+    /// Reads the OPT fields out of a record decoded with type 41. `offset` is only
+    /// used to place errors.
+    pub fn from_record(record: &DnsAnswer, offset: usize) -> ParseResult<Edns> {
+        // RFC 6891 6.1.2: the owner has to be the root, anything else is a format error
+        if !record.name.is_root() {
+            return Err(DnsParseError::OptNotAtRoot { offset });
+        }
+        let bad_rdata = || DnsParseError::BadRdata {
+            offset,
+            rtype: TYPE_OPT,
+        };
+        let data = match &record.rdata {
+            RData::Unknown { data, .. } => data,
+            _ => return Err(bad_rdata()),
+        };
+        let mut options = Vec::new();
+        let mut i = 0;
+        while i < data.len() {
+            let header = data.get(i..i + 4).ok_or_else(bad_rdata)?;
+            let code = u16::from_be_bytes([header[0], header[1]]);
+            let len = u16::from_be_bytes([header[2], header[3]]) as usize;
+            let option_data = data.get(i + 4..i + 4 + len).ok_or_else(bad_rdata)?;
+            options.push(EdnsOption {
+                code,
+                data: option_data.to_vec(),
+            });
+            i += 4 + len;
+        }
+        // the ttl field is extended rcode, version, then the DO bit and 15 zero bits
+        let [extended_rcode, version, flags, _] = record.ttl.to_be_bytes();
+        Ok(Edns {
+            udp_payload_size: record.qclass,
+            extended_rcode,
+            version,
+            dnssec_ok: flags & 0b1000_0000 != 0,
+            options,
+        })
+    }
+
+    pub fn to_record(&self) -> DnsAnswer {
+        let mut data = Vec::new();
+        for option in &self.options {
+            data.extend_from_slice(&option.code.to_be_bytes());
+            data.extend_from_slice(&(option.data.len() as u16).to_be_bytes());
+            data.extend_from_slice(&option.data);
+        }
+        let flags = (self.dnssec_ok as u8) << 7;
+        DnsAnswer {
+            name: DnsName::root(),
+            qtype: TYPE_OPT,
+            qclass: self.udp_payload_size,
+            ttl: u32::from_be_bytes([self.extended_rcode, self.version, flags, 0]),
+            rdata: RData::Unknown {
+                rtype: TYPE_OPT,
+                data,
+            },
+        }
+    }
+
+    /// The payload size a requester can actually take: anything under 512 is
+    /// treated as 512 (RFC 6891 6.2.5), and we never go past our own limit.
+    pub fn effective_payload_size(&self) -> usize {
+        self.udp_payload_size
+            .clamp(CLASSIC_UDP_PAYLOAD_SIZE, SERVER_UDP_PAYLOAD_SIZE) as usize
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::structs::{DnsHeader, DnsMessage, DnsQuestion};
+
+    #[test]
+    fn test_edns_record_round_trip() {
+        let edns = Edns {
+            udp_payload_size: 1232,
+            extended_rcode: 1,
+            version: 0,
+            dnssec_ok: true,
+            options: vec![
+                EdnsOption {
+                    code: 10, // COOKIE
+                    data: vec![1, 2, 3, 4, 5, 6, 7, 8],
+                },
+                EdnsOption {
+                    code: 12, // padding
+                    data: Vec::new(),
+                },
+            ],
+        };
+        let record = edns.to_record();
+        assert_eq!(record.qclass, 1232);
+        assert_eq!(record.ttl, 0x0100_8000);
+        assert_eq!(Edns::from_record(&record, 0).unwrap(), edns);
+    }
+
+    #[test]
+    fn test_edns_bad_option_length() {
+        let record = DnsAnswer {
+            name: DnsName::root(),
+            qtype: TYPE_OPT,
+            qclass: 4096,
+            ttl: 0,
+            // says 8 bytes of option data but only has 2
+            rdata: RData::Unknown {
+                rtype: TYPE_OPT,
+                data: vec![0, 10, 0, 8, 1, 2],
+            },
+        };
+        assert_eq!(
+            Edns::from_record(&record, 30).unwrap_err(),
+            DnsParseError::BadRdata {
+                offset: 30,
+                rtype: TYPE_OPT
+            }
+        );
+    }
+
+    #[test]
+    fn test_edns_owner_not_root() {
+        let mut record = Edns::default().to_record();
+        record.name = "example.com".parse().unwrap();
+        assert_eq!(
+            Edns::from_record(&record, 30).unwrap_err(),
+            DnsParseError::OptNotAtRoot { offset: 30 }
+        );
+    }
+
+    #[test]
+    fn test_dns_message_edns_round_trip() {
+        let message = DnsMessage {
+            header: DnsHeader {
+                questions: 1,
+                resource_entries: 1,
+                ..DnsHeader::default()
+            },
+            questions: vec![DnsQuestion {
+                qname: "example.com".parse().unwrap(),
+                qtype: 1,
+                qclass: 1,
+            }],
+            answers: Vec::new(),
+            authorities: Vec::new(),
+            additionals: Vec::new(),
+            edns: Some(Edns {
+                udp_payload_size: 1400,
+                ..Edns::default()
+            }),
+        };
+        let bytes = message.to_bytes();
+        // the OPT record goes at the end of the additional section
+        assert_eq!(&bytes[bytes.len() - 11..bytes.len() - 8], &[0, 0, 41]);
+        let parsed = DnsMessage::from_bytes(&bytes).unwrap();
+        assert_eq!(parsed, message);
+        assert!(parsed.additionals.is_empty());
+        assert_eq!(parsed.max_udp_payload_size(), 1400);
+    }
+
+    #[test]
+    fn test_dns_message_multiple_opt() {
+        let record = Edns::default().to_record();
+        let message = DnsMessage {
+            header: DnsHeader::default(),
+            questions: Vec::new(),
+            answers: Vec::new(),
+            authorities: Vec::new(),
+            additionals: vec![record.clone(), record],
+            edns: None,
+        };
+        assert_eq!(
+            DnsMessage::from_bytes(&message.to_bytes()).unwrap_err(),
+            DnsParseError::MultipleOpt { offset: 23 }
+        );
+    }
+
+    #[test]
+    fn test_effective_payload_size() {
+        let size = |udp_payload_size| {
+            Edns {
+                udp_payload_size,
+                ..Edns::default()
+            }
+            .effective_payload_size()
+        };
+        assert_eq!(size(0), 512);
+        assert_eq!(size(1232), 1232);
+        assert_eq!(size(65535), 4096);
+    }
+}
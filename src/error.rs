@@ -0,0 +1,78 @@
+//! Errors from decoding messages off the wire, from talking to upstreams and
+//! from reading configuration and zone files.
+
+use thiserror::Error;
+
+/// Everything that can go wrong while decoding a DNS message off the wire.
+/// Offsets are relative to the start of the whole message whenever the decoder
+/// knows where it is inside it.
+#[derive(Debug, Clone, PartialEq, Eq, Error)]
+pub enum DnsParseError {
+    #[error("message truncated at offset {offset}: needed {needed} more bytes")]
+    Truncated { offset: usize, needed: usize },
+    #[error("bad label length byte {len:#04x} at offset {offset}")]
+    BadLabel { offset: usize, len: u8 },
+    #[error("too many compression pointers at offset {offset}")]
+    PointerLoop { offset: usize },
+    #[error("compression pointer at offset {offset} points forward to {target}")]
+    ForwardPointer { offset: usize, target: usize },
+    #[error("domain name at offset {offset} is longer than 255 bytes")]
+    NameTooLong { offset: usize },
+    #[error("{section} count {count} cannot fit in the remaining {remaining} bytes")]
+    BadCount {
+        section: &'static str,
+        count: u16,
+        remaining: usize,
+    },
+    #[error("malformed rdata for record type {rtype} at offset {offset}")]
+    BadRdata { offset: usize, rtype: u16 },
+    #[error("second OPT record at offset {offset}")]
+    MultipleOpt { offset: usize },
+    #[error("OPT record at offset {offset} isn't owned by the root")]
+    OptNotAtRoot { offset: usize },
+    #[error("unsupported record class {qclass} at offset {offset}")]
+    UnsupportedClass { offset: usize, qclass: u16 },
+}
+
+pub type ParseResult<T> = Result<T, DnsParseError>;
+
+/// Why we couldn't get an answer out of an upstream resolver.
+#[derive(Debug, Error)]
+pub enum UpstreamError {
+    #[error("upstream address {address:?} doesn't resolve to anything")]
+    BadAddress { address: String },
+    #[error("no usable reply after {attempts} attempts")]
+    Timeout { attempts: u32 },
+    #[error("socket error talking to upstream: {0}")]
+    Io(#[from] std::io::Error),
+}
+
+/// Why a config file couldn't be loaded.
+#[derive(Debug, Error)]
+pub enum ConfigError {
+    #[error("couldn't read {path}: {source}")]
+    Io {
+        path: String,
+        source: std::io::Error,
+    },
+    #[error("line {line}: {message}")]
+    Syntax { line: usize, message: String },
+}
+
+/// Why a zone couldn't be loaded from its master file.
+#[derive(Debug, Error)]
+pub enum ZoneError {
+    #[error("couldn't read {path}: {source}")]
+    Io {
+        path: String,
+        source: std::io::Error,
+    },
+    #[error("{file}:{line}: {message}")]
+    Syntax {
+        file: String,
+        line: usize,
+        message: String,
+    },
+    #[error("zone {origin}: {message}")]
+    Invalid { origin: String, message: String },
+}
@@ -0,0 +1,33 @@
+//! A DNS message codec and a small forwarding/stub DNS server built on it.
+//!
+//! The codec lives in [`structs`] (header, questions, records and whole
+//! messages), with domain names in [`name`], record data in [`rdata`] and
+//! EDNS(0) in [`edns`]. Every decoder returns a [`DnsParseError`] instead of
+//! panicking, so it is safe to feed untrusted packets to.
+//!
+//! ```
+//! use dns_starter_rust::{DnsMessage, DnsQuestion, RData, TYPE_A};
+//!
+//! let query = DnsMessage::query(0x1234, DnsQuestion::new("example.com".parse().unwrap(), TYPE_A));
+//! let bytes = query.to_bytes();
+//! let parsed = DnsMessage::from_bytes(&bytes).unwrap();
+//! assert_eq!(parsed.questions[0].qname.to_string(), "example.com.");
+//! ```
+
+pub mod cache;
+pub mod config;
+pub mod edns;
+pub mod error;
+pub mod master;
+pub mod name;
+pub mod rdata;
+pub mod server;
+pub mod structs;
+pub mod upstream;
+pub mod zone;
+
+pub use edns::{Edns, EdnsOption};
+pub use error::{ConfigError, DnsParseError, ParseResult, UpstreamError, ZoneError};
+pub use name::{DnsName, NameError};
+pub use rdata::*;
+pub use structs::*;
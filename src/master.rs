@@ -0,0 +1,696 @@
+//! Reading zones written as RFC 1035 master files (section 5).
+//!
+//! Besides records a file can hold `$ORIGIN`, `$TTL` (RFC 2308 4) and
+//! `$INCLUDE` directives. Names without a trailing dot are relative to the
+//! current origin and `@` is the origin itself, a record starting with blank
+//! space belongs to the previous record's owner, parentheses let a record run
+//! over several lines and `;` starts a comment. TTLs can be plain seconds or
+//! BIND style like `1h30m`. Record data is either in its type's usual text form
+//! or the generic `\# <length> <hex>` form from RFC 3597 5, which works for
+//! any type.
+
+use std::fs;
+use std::net::{Ipv4Addr, Ipv6Addr};
+use std::path::Path;
+use std::slice;
+use std::str::FromStr;
+
+use crate::error::ZoneError;
+use crate::name::DnsName;
+use crate::rdata::*;
+use crate::structs::DnsAnswer;
+
+/// How deep `$INCLUDE`s can nest, so a file including itself fails instead of
+/// recursing forever.
+const MAX_INCLUDE_DEPTH: usize = 8;
+
+/// Reads the records in a master file. Names are relative to `origin` until a
+/// `$ORIGIN` says otherwise, and `$INCLUDE` paths are relative to the file
+/// doing the including.
+pub fn read_master_file(path: &Path, origin: &DnsName) -> Result<Vec<DnsAnswer>, ZoneError> {
+    let mut parser = Parser::new(origin);
+    parser.read_file(path, 0)?;
+    Ok(parser.records)
+}
+
+/// Like `read_master_file` for text that's already in memory. `$INCLUDE` paths
+/// are relative to the working directory.
+pub fn parse_master(text: &str, origin: &DnsName) -> Result<Vec<DnsAnswer>, ZoneError> {
+    let mut parser = Parser::new(origin);
+    parser.parse(text, "<text>", Path::new("."), 0)?;
+    Ok(parser.records)
+}
+
+/// Reads a single record written as one master file entry, like
+/// `www 300 IN A 192.0.2.1`. With no earlier records to fall back on, the owner
+/// and TTL have to be there.
+pub fn parse_record(text: &str, origin: &DnsName) -> Result<DnsAnswer, ZoneError> {
+    let syntax = |line: usize, message: &str| ZoneError::Syntax {
+        file: "<record>".to_string(),
+        line,
+        message: message.to_string(),
+    };
+    let entries = tokenize(text.trim_start()).map_err(|(line, message)| syntax(line, &message))?;
+    let [entry] = &entries[..] else {
+        return Err(syntax(1, "expected exactly one record"));
+    };
+    if entry.tokens[0].text.starts_with('$') && !entry.tokens[0].quoted {
+        return Err(syntax(entry.line, "expected a record, not a directive"));
+    }
+    (Parser::new(origin).record(entry)).map_err(|message| syntax(entry.line, &message))
+}
+
+/// One field of an entry. Escapes are left in, since names and
+/// character-strings each decode them their own way.
+#[derive(Debug)]
+struct Token {
+    text: String,
+    quoted: bool,
+}
+
+/// A record or directive, with any lines inside parentheses joined up.
+#[derive(Debug)]
+struct Entry {
+    line: usize,
+    /// Starts with blank space, so the owner is the previous record's.
+    blank_owner: bool,
+    tokens: Vec<Token>,
+}
+
+/// Splits a file into entries, or says which line it couldn't make sense of.
+fn tokenize(text: &str) -> Result<Vec<Entry>, (usize, String)> {
+    let mut entries = Vec::new();
+    let mut entry: Option<Entry> = None;
+    let mut line = 1;
+    let mut depth = 0;
+    let mut at_line_start = true;
+    let mut chars = text.chars().peekable();
+    while let Some(c) = chars.next() {
+        if at_line_start && depth == 0 {
+            entries.extend(entry.take().filter(|entry| !entry.tokens.is_empty()));
+            entry = Some(Entry {
+                line,
+                blank_owner: c == ' ' || c == '\t',
+                tokens: Vec::new(),
+            });
+        }
+        at_line_start = false;
+        let tokens = &mut entry.as_mut().expect("started above").tokens;
+        match c {
+            '\n' => {
+                line += 1;
+                at_line_start = true;
+            }
+            ' ' | '\t' | '\r' => {}
+            ';' => while chars.next_if(|&c| c != '\n').is_some() {},
+            '(' => depth += 1,
+            ')' if depth == 0 => return Err((line, "unbalanced )".to_string())),
+            ')' => depth -= 1,
+            '"' => {
+                let mut text = String::new();
+                loop {
+                    match chars.next() {
+                        None | Some('\n') => {
+                            return Err((line, "unterminated quoted string".to_string()))
+                        }
+                        Some('"') => break,
+                        Some('\\') => {
+                            text.push('\\');
+                            text.extend(chars.next_if(|&c| c != '\n'));
+                        }
+                        Some(c) => text.push(c),
+                    }
+                }
+                tokens.push(Token { text, quoted: true });
+            }
+            _ => {
+                let mut text = String::new();
+                let mut c = Some(c);
+                while let Some(next) = c {
+                    text.push(next);
+                    if next == '\\' {
+                        text.extend(chars.next_if(|&c| c != '\n'));
+                    }
+                    c = chars.next_if(|c| !" \t\r\n();\"".contains(*c));
+                }
+                tokens.push(Token {
+                    text,
+                    quoted: false,
+                });
+            }
+        }
+    }
+    if depth > 0 {
+        let start = entry.as_ref().map_or(line, |entry| entry.line);
+        return Err((start, "( is never closed".to_string()));
+    }
+    entries.extend(entry.filter(|entry| !entry.tokens.is_empty()));
+    Ok(entries)
+}
+
+/// What carries over from one entry to the next.
+struct Parser {
+    origin: DnsName,
+    default_ttl: Option<u32>,
+    last_owner: Option<DnsName>,
+    last_ttl: Option<u32>,
+    records: Vec<DnsAnswer>,
+}
+
+impl Parser {
+    fn new(origin: &DnsName) -> Parser {
+        Parser {
+            origin: origin.clone(),
+            default_ttl: None,
+            last_owner: None,
+            last_ttl: None,
+            records: Vec::new(),
+        }
+    }
+
+    fn read_file(&mut self, path: &Path, depth: usize) -> Result<(), ZoneError> {
+        let text = fs::read_to_string(path).map_err(|source| ZoneError::Io {
+            path: path.display().to_string(),
+            source,
+        })?;
+        let dir = path.parent().unwrap_or(Path::new("."));
+        self.parse(&text, &path.display().to_string(), dir, depth)
+    }
+
+    fn parse(&mut self, text: &str, file: &str, dir: &Path, depth: usize) -> Result<(), ZoneError> {
+        let syntax = |line: usize, message: String| ZoneError::Syntax {
+            file: file.to_string(),
+            line,
+            message,
+        };
+        let entries = tokenize(text).map_err(|(line, message)| syntax(line, message))?;
+        for entry in entries {
+            let first = &entry.tokens[0];
+            if entry.blank_owner || first.quoted || !first.text.starts_with('$') {
+                let record = self
+                    .record(&entry)
+                    .map_err(|message| syntax(entry.line, message))?;
+                self.records.push(record);
+                continue;
+            }
+            let args = &entry.tokens[1..];
+            match first.text.to_ascii_uppercase().as_str() {
+                "$ORIGIN" => {
+                    let [name] = args else {
+                        return Err(syntax(entry.line, "$ORIGIN takes a name".to_string()));
+                    };
+                    self.origin = self.name(name).map_err(|e| syntax(entry.line, e))?;
+                }
+                "$TTL" => {
+                    let [ttl] = args else {
+                        return Err(syntax(entry.line, "$TTL takes a TTL".to_string()));
+                    };
+                    let ttl = parse_ttl(&ttl.text)
+                        .ok_or_else(|| syntax(entry.line, format!("bad TTL {:?}", ttl.text)))?;
+                    self.default_ttl = Some(ttl);
+                }
+                "$INCLUDE" => {
+                    let (path, origin) = match args {
+                        [path] => (path, self.origin.clone()),
+                        [path, origin] => {
+                            (path, self.name(origin).map_err(|e| syntax(entry.line, e))?)
+                        }
+                        _ => {
+                            let message = "$INCLUDE takes a file and maybe an origin";
+                            return Err(syntax(entry.line, message.to_string()));
+                        }
+                    };
+                    if depth >= MAX_INCLUDE_DEPTH {
+                        let message = "$INCLUDEs nested too deeply".to_string();
+                        return Err(syntax(entry.line, message));
+                    }
+                    // the included file can't change our origin (RFC 1035 5.1)
+                    let outer_origin = std::mem::replace(&mut self.origin, origin);
+                    self.read_file(&dir.join(&path.text), depth + 1)?;
+                    self.origin = outer_origin;
+                }
+                other => return Err(syntax(entry.line, format!("unknown directive {}", other))),
+            }
+        }
+        Ok(())
+    }
+
+    /// `<owner> [<TTL>] [<class>] <type> <rdata>`, where the TTL and class can
+    /// come in either order.
+    fn record(&mut self, entry: &Entry) -> Result<DnsAnswer, String> {
+        let tokens = &entry.tokens;
+        let mut next = 0;
+        let owner = if entry.blank_owner {
+            (self.last_owner.clone())
+                .ok_or("no owner name, and no earlier record to take it from")?
+        } else {
+            next = 1;
+            self.name(&tokens[0])?
+        };
+        let mut ttl = None;
+        let mut class = None;
+        while let Some(token) = tokens.get(next).filter(|token| !token.quoted) {
+            let upper = token.text.to_ascii_uppercase();
+            if class.is_none()
+                && (["IN", "CS", "CH", "HS"].contains(&upper.as_str())
+                    || upper.starts_with("CLASS"))
+            {
+                class = Some(upper);
+            } else if let Some(value) = parse_ttl(&token.text).filter(|_| ttl.is_none()) {
+                ttl = Some(value);
+            } else {
+                break;
+            }
+            next += 1;
+        }
+        if let Some(class) = class.filter(|class| class != "IN" && class != "CLASS1") {
+            return Err(format!("class {} isn't supported, only IN", class));
+        }
+        let rtype = tokens.get(next).ok_or("record has no type")?;
+        let rtype = type_from_name(&rtype.text)
+            .ok_or_else(|| format!("unknown record type {:?}", rtype.text))?;
+        let fields: Vec<&Token> = tokens[next + 1..].iter().collect();
+        let rdata = self.rdata(rtype, &fields)?;
+        let ttl = match ttl {
+            Some(ttl) => {
+                self.last_ttl = Some(ttl);
+                ttl
+            }
+            None => (self.default_ttl.or(self.last_ttl))
+                .ok_or("no TTL given, and no $TTL or earlier record to take it from")?,
+        };
+        self.last_owner = Some(owner.clone());
+        Ok(DnsAnswer::new(owner, ttl, rdata))
+    }
+
+    /// A domain name, relative to the origin unless it ends in a dot.
+    fn name(&self, token: &Token) -> Result<DnsName, String> {
+        if token.text == "@" {
+            return Ok(self.origin.clone());
+        }
+        let bad_name = |e| format!("bad name {:?}: {}", token.text, e);
+        let name: DnsName = token.text.parse().map_err(bad_name)?;
+        // an odd run of backslashes before the last dot escapes it
+        let trailing_backslashes = (token.text.strip_suffix('.'))
+            .map(|rest| rest.len() - rest.trim_end_matches('\\').len());
+        if trailing_backslashes.is_some_and(|count| count % 2 == 0) {
+            return Ok(name);
+        }
+        DnsName::from_labels(name.labels().chain(self.origin.labels())).map_err(bad_name)
+    }
+
+    fn rdata(&self, rtype: u16, fields: &[&Token]) -> Result<RData, String> {
+        if let [first, rest @ ..] = fields {
+            if !first.quoted && first.text == "\\#" {
+                return generic_rdata(rtype, rest);
+            }
+        }
+        let mut fields = Fields {
+            tokens: fields.iter(),
+            rtype,
+        };
+        let rdata = match rtype {
+            TYPE_A => RData::A(fields.parse::<Ipv4Addr>("address")?),
+            TYPE_AAAA => RData::Aaaa(fields.parse::<Ipv6Addr>("address")?),
+            TYPE_NS => RData::Ns(self.name(fields.next()?)?),
+            TYPE_CNAME => RData::Cname(self.name(fields.next()?)?),
+            TYPE_PTR => RData::Ptr(self.name(fields.next()?)?),
+            TYPE_DNAME => RData::Dname(self.name(fields.next()?)?),
+            TYPE_MX => RData::Mx {
+                preference: fields.parse("preference")?,
+                exchange: self.name(fields.next()?)?,
+            },
+            TYPE_TXT => {
+                let strings = (fields.tokens.by_ref())
+                    .map(|token| character_string(&token.text))
+                    .collect::<Result<Vec<_>, _>>()?;
+                if strings.is_empty() {
+                    return Err("TXT record has no strings".to_string());
+                }
+                RData::Txt(strings)
+            }
+            TYPE_SOA => RData::Soa {
+                mname: self.name(fields.next()?)?,
+                rname: self.name(fields.next()?)?,
+                serial: fields.parse("serial")?,
+                refresh: fields.ttl("refresh")?,
+                retry: fields.ttl("retry")?,
+                expire: fields.ttl("expire")?,
+                minimum: fields.ttl("minimum")?,
+            },
+            TYPE_SRV => RData::Srv {
+                priority: fields.parse("priority")?,
+                weight: fields.parse("weight")?,
+                port: fields.parse("port")?,
+                target: self.name(fields.next()?)?,
+            },
+            TYPE_CAA => {
+                let flags = fields.parse("flags")?;
+                let tag = fields.next()?;
+                if tag.quoted
+                    || tag.text.is_empty()
+                    || tag.text.len() > 255
+                    || !tag.text.bytes().all(|b| b.is_ascii_alphanumeric())
+                {
+                    return Err(format!("bad CAA tag {:?}", tag.text));
+                }
+                RData::Caa {
+                    flags,
+                    tag: tag.text.clone(),
+                    value: character_string(&fields.next()?.text)?,
+                }
+            }
+            _ => {
+                return Err(format!(
+                    "{} records have to be written in the \\# form",
+                    type_name(rtype)
+                ))
+            }
+        };
+        fields.finish()?;
+        Ok(rdata)
+    }
+}
+
+/// The record data of one record, taken a field at a time.
+struct Fields<'a> {
+    tokens: slice::Iter<'a, &'a Token>,
+    rtype: u16,
+}
+
+impl<'a> Fields<'a> {
+    fn next(&mut self) -> Result<&'a Token, String> {
+        (self.tokens.next().copied())
+            .ok_or_else(|| format!("{} record is missing fields", type_name(self.rtype)))
+    }
+
+    fn parse<T: FromStr>(&mut self, what: &str) -> Result<T, String> {
+        let token = self.next()?;
+        (token.text.parse()).map_err(|_| format!("bad {} {:?}", what, token.text))
+    }
+
+    fn ttl(&mut self, what: &str) -> Result<u32, String> {
+        let token = self.next()?;
+        parse_ttl(&token.text).ok_or_else(|| format!("bad {} {:?}", what, token.text))
+    }
+
+    fn finish(mut self) -> Result<(), String> {
+        match self.tokens.next() {
+            Some(extra) => Err(format!(
+                "unexpected {:?} after the {} record data",
+                extra.text,
+                type_name(self.rtype)
+            )),
+            None => Ok(()),
+        }
+    }
+}
+
+/// `\# <length> <hex>...`, with the hex split over as many fields as you like.
+/// It's decoded as if it came off the wire, so known types come out typed.
+fn generic_rdata(rtype: u16, fields: &[&Token]) -> Result<RData, String> {
+    let (len, hex) = fields.split_first().ok_or("\\# needs a length")?;
+    let len: usize = (len.text.parse()).map_err(|_| format!("bad rdata length {:?}", len.text))?;
+    let hex: String = hex.iter().map(|token| token.text.as_str()).collect();
+    let nibbles: Vec<u8> = (hex.chars())
+        .map(|c| c.to_digit(16).map(|digit| digit as u8))
+        .collect::<Option<_>>()
+        .ok_or_else(|| format!("bad hex rdata {:?}", hex))?;
+    let pairs = nibbles.chunks_exact(2);
+    if !pairs.remainder().is_empty() {
+        return Err(format!("odd number of hex digits in {:?}", hex));
+    }
+    let data: Vec<u8> = pairs.map(|pair| pair[0] << 4 | pair[1]).collect();
+    if data.len() != len {
+        return Err(format!("rdata is {} bytes, not {}", data.len(), len));
+    }
+    RData::decode(rtype, &data, data.len(), &data)
+        .map_err(|e| format!("bad {} rdata: {}", type_name(rtype), e))
+}
+
+/// A <character-string>, with `\X` and `\DDD` escapes decoded.
+fn character_string(text: &str) -> Result<Vec<u8>, String> {
+    let bad_escape = || format!("bad escape in {:?}", text);
+    let mut bytes = Vec::new();
+    let mut input = text.bytes();
+    while let Some(byte) = input.next() {
+        if byte != b'\\' {
+            bytes.push(byte);
+            continue;
+        }
+        let escaped = input.next().ok_or_else(bad_escape)?;
+        if !escaped.is_ascii_digit() {
+            bytes.push(escaped);
+            continue;
+        }
+        let digits = [
+            escaped,
+            input.next().unwrap_or(0),
+            input.next().unwrap_or(0),
+        ];
+        if !digits.iter().all(u8::is_ascii_digit) {
+            return Err(bad_escape());
+        }
+        let value = (digits.iter()).fold(0u32, |acc, digit| acc * 10 + (digit - b'0') as u32);
+        bytes.push(u8::try_from(value).map_err(|_| bad_escape())?);
+    }
+    if bytes.len() > 255 {
+        return Err(format!("string is {} bytes, the limit is 255", bytes.len()));
+    }
+    Ok(bytes)
+}
+
+/// Seconds, either plain or with BIND's units: `1w2d`, `90m`, `1h30m`.
+fn parse_ttl(text: &str) -> Option<u32> {
+    if text.bytes().all(|b| b.is_ascii_digit()) {
+        return text.parse().ok();
+    }
+    let mut total: u32 = 0;
+    let mut value: Option<u32> = None;
+    for c in text.chars() {
+        if let Some(digit) = c.to_digit(10) {
+            value = Some(value.unwrap_or(0).checked_mul(10)?.checked_add(digit)?);
+            continue;
+        }
+        let unit = match c.to_ascii_lowercase() {
+            's' => 1,
+            'm' => 60,
+            'h' => 60 * 60,
+            'd' => 24 * 60 * 60,
+            'w' => 7 * 24 * 60 * 60,
+            _ => return None,
+        };
+        total = total.checked_add(value.take()?.checked_mul(unit)?)?;
+    }
+    // trailing digits without a unit are seconds
+    total.checked_add(value.unwrap_or(0))
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use std::env;
+    use std::process;
+
+    fn name(s: &str) -> DnsName {
+        s.parse().unwrap()
+    }
+
+    #[test]
+    fn test_master_file_records() {
+        let records = parse_master(
+            r#"
+$TTL 1h
+@   IN  SOA ns1 hostmaster (
+            2024010101 ; serial
+            2h 15m 2w  ; refresh, retry, expire
+            300 )      ; minimum
+        NS  ns1
+        MX  10 mail.example.net.
+ns1 300 IN A 192.0.2.1
+    IN 600 AAAA 2001:db8::1
+$ORIGIN sub.example.com.
+www CNAME @
+txt TXT "hello \"world\"" plain \065\t
+_sip._udp SRV 10 60 5060 sip.example.com.
+@ CAA 0 issue "ca.example.net"
+odd TYPE65280 \# 3 ab CDEF
+hex A \# 4 C0000202
+"#,
+            &name("example.com"),
+        )
+        .unwrap();
+        let expected = [
+            DnsAnswer::new(
+                name("example.com"),
+                3600,
+                RData::Soa {
+                    mname: name("ns1.example.com"),
+                    rname: name("hostmaster.example.com"),
+                    serial: 2024010101,
+                    refresh: 7200,
+                    retry: 900,
+                    expire: 1209600,
+                    minimum: 300,
+                },
+            ),
+            DnsAnswer::new(
+                name("example.com"),
+                3600,
+                RData::Ns(name("ns1.example.com")),
+            ),
+            DnsAnswer::new(
+                name("example.com"),
+                3600,
+                RData::Mx {
+                    preference: 10,
+                    exchange: name("mail.example.net"),
+                },
+            ),
+            DnsAnswer::new(
+                name("ns1.example.com"),
+                300,
+                RData::A([192, 0, 2, 1].into()),
+            ),
+            DnsAnswer::new(
+                name("ns1.example.com"),
+                600,
+                RData::Aaaa("2001:db8::1".parse().unwrap()),
+            ),
+            DnsAnswer::new(
+                name("www.sub.example.com"),
+                3600,
+                RData::Cname(name("sub.example.com")),
+            ),
+            DnsAnswer::new(
+                name("txt.sub.example.com"),
+                3600,
+                RData::Txt(vec![
+                    b"hello \"world\"".to_vec(),
+                    b"plain".to_vec(),
+                    b"At".to_vec(),
+                ]),
+            ),
+            DnsAnswer::new(
+                name("_sip._udp.sub.example.com"),
+                3600,
+                RData::Srv {
+                    priority: 10,
+                    weight: 60,
+                    port: 5060,
+                    target: name("sip.example.com"),
+                },
+            ),
+            DnsAnswer::new(
+                name("sub.example.com"),
+                3600,
+                RData::Caa {
+                    flags: 0,
+                    tag: "issue".to_string(),
+                    value: b"ca.example.net".to_vec(),
+                },
+            ),
+            DnsAnswer::new(
+                name("odd.sub.example.com"),
+                3600,
+                RData::Unknown {
+                    rtype: 65280,
+                    data: vec![0xab, 0xcd, 0xef],
+                },
+            ),
+            DnsAnswer::new(
+                name("hex.sub.example.com"),
+                3600,
+                RData::A([192, 0, 2, 2].into()),
+            ),
+        ];
+        assert_eq!(records, expected);
+    }
+
+    #[test]
+    fn test_master_file_ttl_defaults() {
+        // without $TTL a record takes the last TTL given
+        let records = parse_master(
+            "a 60 A 192.0.2.1\nb A 192.0.2.2\n$TTL 1d\nc A 192.0.2.3\nd 5 A 192.0.2.4",
+            &name("example"),
+        )
+        .unwrap();
+        let ttls: Vec<u32> = records.iter().map(|record| record.ttl).collect();
+        assert_eq!(ttls, [60, 60, 86400, 5]);
+    }
+
+    #[test]
+    fn test_master_file_include() {
+        let dir = env::temp_dir().join(format!("dns-master-include-{}", process::id()));
+        fs::create_dir_all(dir.join("hosts")).unwrap();
+        fs::write(
+            dir.join("zone"),
+            "$TTL 60\n$INCLUDE hosts/lab lab.example.\nafter A 192.0.2.3\n",
+        )
+        .unwrap();
+        // the include changes its own origin but not the including file's
+        fs::write(
+            dir.join("hosts/lab"),
+            "box1 A 192.0.2.1\n$ORIGIN other.example.\nbox2 A 192.0.2.2\n",
+        )
+        .unwrap();
+        let records = read_master_file(&dir.join("zone"), &name("example")).unwrap();
+        fs::remove_dir_all(&dir).unwrap();
+        let names: Vec<String> = records.iter().map(|r| r.name.to_string()).collect();
+        assert_eq!(
+            names,
+            ["box1.lab.example.", "box2.other.example.", "after.example."]
+        );
+    }
+
+    #[test]
+    fn test_master_file_errors_have_line_numbers() {
+        let line_of = |text: &str| match parse_master(text, &name("example")) {
+            Err(ZoneError::Syntax { line, .. }) => line,
+            other => panic!("expected a syntax error, got {:?}", other),
+        };
+        assert_eq!(line_of("a 60 A 192.0.2.1\nb 60 BOGUS x"), 2);
+        assert_eq!(line_of("a A 192.0.2.1"), 1);
+        assert_eq!(line_of("\n\na 60 A 300.0.2.1"), 3);
+        assert_eq!(line_of("a 60 MX (10\n\n"), 1);
+        assert_eq!(line_of("a 60 A 192.0.2.1 )"), 1);
+        assert_eq!(line_of(" 60 A 192.0.2.1"), 1);
+        assert_eq!(line_of("a 60 CH A 192.0.2.1"), 1);
+        assert_eq!(line_of("a 60 A 192.0.2.1 extra"), 1);
+        assert_eq!(line_of("a 60 TYPE999 1 2 3"), 1);
+        assert_eq!(line_of("a 60 A \\# 3 c00002"), 1);
+        assert_eq!(line_of("$TTL\n$ORIGIN"), 1);
+        assert_eq!(line_of("a 60 TXT \"open"), 1);
+        assert_eq!(line_of(&format!("a 60 TXT {}", "x".repeat(300))), 1);
+        assert_eq!(line_of(&format!("a 60 CAA 0 {} \"v\"", "t".repeat(300))), 1);
+    }
+
+    #[test]
+    fn test_parse_record() {
+        let record = parse_record("  www 1h in a 192.0.2.1 ; web", &name("example.com")).unwrap();
+        assert_eq!(
+            record,
+            DnsAnswer::new(
+                name("www.example.com"),
+                3600,
+                RData::A([192, 0, 2, 1].into())
+            )
+        );
+        let origin = name("example.com");
+        assert!(parse_record("www A 192.0.2.1", &origin).is_err());
+        assert!(parse_record("$TTL 60", &origin).is_err());
+        assert!(parse_record("a 60 A 192.0.2.1\nb 60 A 192.0.2.2", &origin).is_err());
+        assert!(parse_record("", &origin).is_err());
+    }
+
+    #[test]
+    fn test_parse_ttl() {
+        assert_eq!(parse_ttl("300"), Some(300));
+        assert_eq!(parse_ttl("1h30m"), Some(5400));
+        assert_eq!(parse_ttl("1W"), Some(604800));
+        assert_eq!(parse_ttl("2m5"), Some(125));
+        assert_eq!(parse_ttl("h"), None);
+        assert_eq!(parse_ttl("A"), None);
+        assert_eq!(parse_ttl("99999999999"), None);
+    }
+}
@@ -0,0 +1,364 @@
+//! Domain names as sequences of raw labels.
+
+use std::cmp::Ordering;
+use std::fmt;
+use std::hash::{Hash, Hasher};
+use std::str::FromStr;
+
+use thiserror::Error;
+
+/// Longest a label can be; the two high bits of the length byte are reserved.
+pub const MAX_LABEL_LEN: usize = 63;
+/// Longest a name can be on the wire, counting length bytes and the terminator.
+pub const MAX_NAME_LEN: usize = 255;
+
+#[derive(Debug, Clone, PartialEq, Eq, Error)]
+pub enum NameError {
+    #[error("label is {len} bytes long, the limit is 63")]
+    LabelTooLong { len: usize },
+    #[error("name is {len} bytes long on the wire, the limit is 255")]
+    NameTooLong { len: usize },
+    #[error("empty label in {name:?}")]
+    EmptyLabel { name: String },
+    #[error("bad escape sequence in {name:?}")]
+    BadEscape { name: String },
+}
+
+/// A domain name kept as its raw labels, so labels containing dots or arbitrary
+/// bytes survive intact. Comparison, hashing and ordering ignore ASCII case, and
+/// ordering is the canonical DNS order from RFC 4034 6.1.
+#[derive(Clone, Default)]
+pub struct DnsName {
+    labels: Vec<Vec<u8>>,
+}
+
+impl DnsName {
+    /// The root name, which has no labels.
+    pub fn root() -> DnsName {
+        DnsName::default()
+    }
+
+    pub fn from_labels<L: Into<Vec<u8>>>(
+        labels: impl IntoIterator<Item = L>,
+    ) -> Result<DnsName, NameError> {
+        let name = DnsName {
+            labels: labels.into_iter().map(Into::into).collect(),
+        };
+        for label in &name.labels {
+            if label.is_empty() {
+                return Err(NameError::EmptyLabel {
+                    name: name.to_string(),
+                });
+            }
+            if label.len() > MAX_LABEL_LEN {
+                return Err(NameError::LabelTooLong { len: label.len() });
+            }
+        }
+        if name.wire_len() > MAX_NAME_LEN {
+            return Err(NameError::NameTooLong {
+                len: name.wire_len(),
+            });
+        }
+        Ok(name)
+    }
+
+    pub fn is_root(&self) -> bool {
+        self.labels.is_empty()
+    }
+
+    /// Labels from the leftmost (most specific) to the rightmost.
+    pub fn labels(&self) -> impl DoubleEndedIterator<Item = &[u8]> + ExactSizeIterator {
+        self.labels.iter().map(Vec::as_slice)
+    }
+
+    pub fn label_count(&self) -> usize {
+        self.labels.len()
+    }
+
+    /// The name with its leftmost label removed, or None for the root.
+    pub fn parent(&self) -> Option<DnsName> {
+        if self.is_root() {
+            return None;
+        }
+        Some(DnsName {
+            labels: self.labels[1..].to_vec(),
+        })
+    }
+
+    /// The name with `label` added on the left.
+    pub fn child(&self, label: &[u8]) -> Result<DnsName, NameError> {
+        DnsName::from_labels(std::iter::once(label).chain(self.labels()))
+    }
+
+    /// The name made of the rightmost `count` labels.
+    pub fn suffix(&self, count: usize) -> DnsName {
+        DnsName {
+            labels: self.labels[self.labels.len() - count.min(self.labels.len())..].to_vec(),
+        }
+    }
+
+    /// Whether this name is `other` or anywhere below it.
+    pub fn is_subdomain_of(&self, other: &DnsName) -> bool {
+        self.labels.len() >= other.labels.len()
+            && self
+                .labels
+                .iter()
+                .rev()
+                .zip(other.labels.iter().rev())
+                .all(|(a, b)| a.eq_ignore_ascii_case(b))
+    }
+
+    /// Bytes the uncompressed name takes on the wire.
+    pub fn wire_len(&self) -> usize {
+        self.labels
+            .iter()
+            .map(|label| label.len() + 1)
+            .sum::<usize>()
+            + 1
+    }
+
+    /// The uncompressed wire form: length-prefixed labels and a zero byte.
+    pub fn to_wire(&self) -> Vec<u8> {
+        let mut bytes = Vec::with_capacity(self.wire_len());
+        for label in &self.labels {
+            bytes.push(label.len() as u8);
+            bytes.extend_from_slice(label);
+        }
+        bytes.push(0);
+        bytes
+    }
+
+    fn lowercase_labels(&self) -> impl DoubleEndedIterator<Item = Vec<u8>> + '_ {
+        self.labels.iter().map(|label| label.to_ascii_lowercase())
+    }
+}
+
+impl PartialEq for DnsName {
+    fn eq(&self, other: &DnsName) -> bool {
+        self.labels.len() == other.labels.len()
+            && self
+                .labels
+                .iter()
+                .zip(&other.labels)
+                .all(|(a, b)| a.eq_ignore_ascii_case(b))
+    }
+}
+
+impl Eq for DnsName {}
+
+impl Hash for DnsName {
+    fn hash<H: Hasher>(&self, state: &mut H) {
+        self.labels.len().hash(state);
+        for label in self.lowercase_labels() {
+            label.hash(state);
+        }
+    }
+}
+
+impl Ord for DnsName {
+    /// Canonical order: compare the lowercased labels starting from the right,
+    /// with a name sorting before everything below it.
+    fn cmp(&self, other: &DnsName) -> Ordering {
+        self.lowercase_labels()
+            .rev()
+            .cmp(other.lowercase_labels().rev())
+    }
+}
+
+impl PartialOrd for DnsName {
+    fn partial_cmp(&self, other: &DnsName) -> Option<Ordering> {
+        Some(self.cmp(other))
+    }
+}
+
+impl fmt::Display for DnsName {
+    /// Presentation format, fully qualified, with special and non-printable
+    /// bytes escaped (RFC 1035 5.1, RFC 4343 2.1).
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        if self.is_root() {
+            return f.write_str(".");
+        }
+        for label in &self.labels {
+            for &byte in label {
+                match byte {
+                    b'.' | b'\\' | b'"' | b'(' | b')' | b';' | b'@' | b'$' => {
+                        write!(f, "\\{}", byte as char)?
+                    }
+                    0x21..=0x7E => write!(f, "{}", byte as char)?,
+                    _ => write!(f, "\\{:03}", byte)?,
+                }
+            }
+            f.write_str(".")?;
+        }
+        Ok(())
+    }
+}
+
+impl fmt::Debug for DnsName {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "DnsName({:?})", self.to_string())
+    }
+}
+
+impl FromStr for DnsName {
+    type Err = NameError;
+
+    /// Parses presentation format. A trailing dot is optional since every name
+    /// here is absolute; "." and "" are the root.
+    fn from_str(s: &str) -> Result<DnsName, NameError> {
+        if s == "." || s.is_empty() {
+            return Ok(DnsName::root());
+        }
+        let bad_escape = || NameError::BadEscape {
+            name: s.to_string(),
+        };
+        let mut labels = Vec::new();
+        let mut label = Vec::new();
+        let mut bytes = s.bytes();
+        while let Some(byte) = bytes.next() {
+            match byte {
+                b'.' => {
+                    if label.is_empty() {
+                        return Err(NameError::EmptyLabel {
+                            name: s.to_string(),
+                        });
+                    }
+                    labels.push(std::mem::take(&mut label));
+                }
+                b'\\' => {
+                    let escaped = bytes.next().ok_or_else(bad_escape)?;
+                    if escaped.is_ascii_digit() {
+                        // \DDD is a byte value in decimal
+                        let second = bytes.next().ok_or_else(bad_escape)?;
+                        let third = bytes.next().ok_or_else(bad_escape)?;
+                        let digits = [escaped, second, third];
+                        if !digits.iter().all(u8::is_ascii_digit) {
+                            return Err(bad_escape());
+                        }
+                        let value = digits
+                            .iter()
+                            .fold(0u32, |acc, digit| acc * 10 + (digit - b'0') as u32);
+                        label.push(u8::try_from(value).map_err(|_| bad_escape())?);
+                    } else {
+                        label.push(escaped);
+                    }
+                }
+                _ => label.push(byte),
+            }
+        }
+        if !label.is_empty() {
+            labels.push(label);
+        }
+        DnsName::from_labels(labels)
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use std::collections::HashSet;
+
+    fn name(s: &str) -> DnsName {
+        s.parse().unwrap()
+    }
+
+    #[test]
+    fn test_dns_name_case_insensitive_eq_and_hash() {
+        assert_eq!(name("WWW.Example.COM"), name("www.example.com."));
+        assert_ne!(name("www.example.com"), name("www.example.org"));
+        let set = HashSet::from([name("Example.com")]);
+        assert!(set.contains(&name("eXaMpLe.CoM")));
+    }
+
+    #[test]
+    fn test_dns_name_child() {
+        assert_eq!(
+            name("example.com").child(b"*").unwrap(),
+            name("*.example.com")
+        );
+        assert_eq!(DnsName::root().child(b"com").unwrap(), name("com"));
+        assert!(name("example.com").child(b"").is_err());
+        assert!(name("example.com").child(&[b'a'; 64]).is_err());
+    }
+
+    #[test]
+    fn test_dns_name_canonical_order() {
+        // the example ordering from RFC 4034 6.1
+        let ordered = vec![
+            name("example"),
+            name("a.example"),
+            name("yljkjljk.a.example"),
+            name("Z.a.example"),
+            name("zABC.a.EXAMPLE"),
+            name("z.example"),
+            name("\\001.z.example"),
+            name("*.z.example"),
+            name("\\200.z.example"),
+        ];
+        let mut shuffled = ordered.clone();
+        shuffled.reverse();
+        shuffled.sort();
+        assert_eq!(shuffled, ordered);
+    }
+
+    #[test]
+    fn test_dns_name_subdomain_and_parent() {
+        let www = name("www.example.com");
+        assert!(www.is_subdomain_of(&name("EXAMPLE.com")));
+        assert!(www.is_subdomain_of(&www));
+        assert!(www.is_subdomain_of(&DnsName::root()));
+        assert!(!www.is_subdomain_of(&name("ww.example.com")));
+        assert!(!name("example.com").is_subdomain_of(&www));
+        assert_eq!(www.parent(), Some(name("example.com")));
+        assert_eq!(name("com").parent(), Some(DnsName::root()));
+        assert_eq!(DnsName::root().parent(), None);
+        assert_eq!(www.suffix(2), name("example.com"));
+    }
+
+    #[test]
+    fn test_dns_name_presentation_escapes() {
+        let odd = DnsName::from_labels([b"a.b".to_vec(), vec![0, b' '], b"com".to_vec()]).unwrap();
+        assert_eq!(odd.to_string(), "a\\.b.\\000\\032.com.");
+        assert_eq!(name(&odd.to_string()), odd);
+        assert_eq!(odd.labels().next(), Some(&b"a.b"[..]));
+        assert_eq!(DnsName::root().to_string(), ".");
+    }
+
+    #[test]
+    fn test_dns_name_from_str_errors() {
+        assert!(matches!(
+            "a..b".parse::<DnsName>(),
+            Err(NameError::EmptyLabel { .. })
+        ));
+        assert!(matches!(
+            "a\\25".parse::<DnsName>(),
+            Err(NameError::BadEscape { .. })
+        ));
+        assert!(matches!(
+            "\\999.com".parse::<DnsName>(),
+            Err(NameError::BadEscape { .. })
+        ));
+        let long_label = "a".repeat(64);
+        assert_eq!(
+            long_label.parse::<DnsName>(),
+            Err(NameError::LabelTooLong { len: 64 })
+        );
+        let long_name = vec!["a".repeat(63); 4].join(".");
+        assert_eq!(
+            long_name.parse::<DnsName>(),
+            Err(NameError::NameTooLong { len: 257 })
+        );
+    }
+
+    #[test]
+    fn test_dns_name_to_wire() {
+        assert_eq!(
+            name("www.example.com").to_wire(),
+            vec![
+                0x03, 0x77, 0x77, 0x77, 0x07, 0x65, 0x78, 0x61, 0x6D, 0x70, 0x6C, 0x65, 0x03, 0x63,
+                0x6F, 0x6D, 0x00
+            ]
+        );
+        assert_eq!(DnsName::root().to_wire(), vec![0]);
+    }
+}
@@ -0,0 +1,578 @@
+//! Typed record data for the common record types, with everything else kept as
+//! raw bytes.
+
+use std::fmt;
+use std::net::{Ipv4Addr, Ipv6Addr};
+
+use crate::error::{DnsParseError, ParseResult};
+use crate::name::DnsName;
+use crate::structs::{
+    offset_in_message, read_name, read_u16, read_u32, write_name, CompressionTable,
+};
+
+pub const CLASS_IN: u16 = 1;
+
+pub const TYPE_A: u16 = 1;
+pub const TYPE_NS: u16 = 2;
+pub const TYPE_CNAME: u16 = 5;
+pub const TYPE_SOA: u16 = 6;
+pub const TYPE_PTR: u16 = 12;
+pub const TYPE_MX: u16 = 15;
+pub const TYPE_TXT: u16 = 16;
+pub const TYPE_AAAA: u16 = 28;
+pub const TYPE_SRV: u16 = 33;
+pub const TYPE_DNAME: u16 = 39;
+pub const TYPE_CAA: u16 = 257;
+
+/// Record types with a mnemonic we know, for reading and writing text formats.
+const TYPE_NAMES: [(u16, &str); 11] = [
+    (TYPE_A, "A"),
+    (TYPE_NS, "NS"),
+    (TYPE_CNAME, "CNAME"),
+    (TYPE_SOA, "SOA"),
+    (TYPE_PTR, "PTR"),
+    (TYPE_MX, "MX"),
+    (TYPE_TXT, "TXT"),
+    (TYPE_AAAA, "AAAA"),
+    (TYPE_SRV, "SRV"),
+    (TYPE_DNAME, "DNAME"),
+    (TYPE_CAA, "CAA"),
+];
+
+/// The mnemonic for a record type, or TYPEnnn (RFC 3597 5) if it hasn't got one.
+pub fn type_name(rtype: u16) -> String {
+    match TYPE_NAMES.iter().find(|(t, _)| *t == rtype) {
+        Some((_, name)) => name.to_string(),
+        None => format!("TYPE{}", rtype),
+    }
+}
+
+/// The record type for a mnemonic or TYPEnnn, ignoring case.
+pub fn type_from_name(name: &str) -> Option<u16> {
+    let upper = name.to_ascii_uppercase();
+    match TYPE_NAMES.iter().find(|(_, n)| *n == upper) {
+        Some((rtype, _)) => Some(*rtype),
+        None => upper.strip_prefix("TYPE")?.parse().ok(),
+    }
+}
+
+/// The decoded contents of a resource record. Anything we don't know how to
+/// decode is kept as raw bytes (RFC 3597) so it can still be passed along.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum RData {
+    A(Ipv4Addr),
+    Aaaa(Ipv6Addr),
+    Cname(DnsName),
+    Ns(DnsName),
+    Ptr(DnsName),
+    /// Redirects everything below the owner name to below this one (RFC 6672).
+    Dname(DnsName),
+    Mx {
+        preference: u16,
+        exchange: DnsName,
+    },
+    /// One entry per <character-string>. Entries longer than 255 bytes are
+    /// written as several <character-string>s.
+    Txt(Vec<Vec<u8>>),
+    Soa {
+        mname: DnsName,
+        rname: DnsName,
+        serial: u32,
+        refresh: u32,
+        retry: u32,
+        expire: u32,
+        minimum: u32,
+    },
+    Srv {
+        priority: u16,
+        weight: u16,
+        port: u16,
+        target: DnsName,
+    },
+    Caa {
+        flags: u8,
+        /// At most 255 bytes, since it goes out with a one byte length. Decoding
+        /// and master files never make a longer one, and writing one panics.
+        tag: String,
+        value: Vec<u8>,
+    },
+    Unknown {
+        rtype: u16,
+        data: Vec<u8>,
+    },
+}
+
+impl RData {
+    /// The record type this data belongs to.
+    pub fn rtype(&self) -> u16 {
+        match self {
+            RData::A(_) => TYPE_A,
+            RData::Aaaa(_) => TYPE_AAAA,
+            RData::Cname(_) => TYPE_CNAME,
+            RData::Ns(_) => TYPE_NS,
+            RData::Ptr(_) => TYPE_PTR,
+            RData::Dname(_) => TYPE_DNAME,
+            RData::Mx { .. } => TYPE_MX,
+            RData::Txt(_) => TYPE_TXT,
+            RData::Soa { .. } => TYPE_SOA,
+            RData::Srv { .. } => TYPE_SRV,
+            RData::Caa { .. } => TYPE_CAA,
+            RData::Unknown { rtype, .. } => *rtype,
+        }
+    }
+
+    pub fn to_bytes(&self) -> Vec<u8> {
+        let mut bytes = Vec::new();
+        self.write_to(&mut bytes, &mut CompressionTable::disabled());
+        bytes
+    }
+
+    /// Appends the record data to a message being built in `bytes`. Only the
+    /// types defined in RFC 1035 get their names compressed; RFC 3597 forbids it
+    /// for anything newer (SRV included) since old resolvers can't decode them.
+    pub fn write_to(&self, bytes: &mut Vec<u8>, names: &mut CompressionTable) {
+        match self {
+            RData::A(addr) => bytes.extend_from_slice(&addr.octets()),
+            RData::Aaaa(addr) => bytes.extend_from_slice(&addr.octets()),
+            RData::Cname(name) | RData::Ns(name) | RData::Ptr(name) => {
+                names.write_name(name, bytes)
+            }
+            RData::Dname(name) => bytes.extend_from_slice(&write_name(name)),
+            RData::Mx {
+                preference,
+                exchange,
+            } => {
+                bytes.extend_from_slice(&preference.to_be_bytes());
+                names.write_name(exchange, bytes);
+            }
+            RData::Txt(strings) => {
+                for string in character_strings(strings) {
+                    bytes.push(string.len() as u8);
+                    bytes.extend_from_slice(string);
+                }
+            }
+            RData::Soa {
+                mname,
+                rname,
+                serial,
+                refresh,
+                retry,
+                expire,
+                minimum,
+            } => {
+                names.write_name(mname, bytes);
+                names.write_name(rname, bytes);
+                for field in [serial, refresh, retry, expire, minimum] {
+                    bytes.extend_from_slice(&field.to_be_bytes());
+                }
+            }
+            RData::Srv {
+                priority,
+                weight,
+                port,
+                target,
+            } => {
+                bytes.extend_from_slice(&priority.to_be_bytes());
+                bytes.extend_from_slice(&weight.to_be_bytes());
+                bytes.extend_from_slice(&port.to_be_bytes());
+                bytes.extend_from_slice(&write_name(target));
+            }
+            RData::Caa { flags, tag, value } => {
+                assert!(tag.len() <= 255, "CAA tag is longer than 255 bytes");
+                bytes.push(*flags);
+                bytes.push(tag.len() as u8);
+                bytes.extend_from_slice(tag.as_bytes());
+                bytes.extend_from_slice(value);
+            }
+            RData::Unknown { data, .. } => bytes.extend_from_slice(data),
+        }
+    }
+
+    /// Decodes `rdlength` bytes of record data from the start of `bytes`. Names
+    /// inside the data may be compressed, so `bytes` should run on to the end of
+    /// `message_bytes` rather than being cut off at `rdlength`.
+    pub fn decode(
+        rtype: u16,
+        bytes: &[u8],
+        rdlength: usize,
+        message_bytes: &[u8],
+    ) -> ParseResult<RData> {
+        let base = offset_in_message(bytes, message_bytes);
+        let bad_rdata = || DnsParseError::BadRdata {
+            offset: base.unwrap_or(0),
+            rtype,
+        };
+        let rdata = bytes
+            .get(..rdlength)
+            .ok_or_else(|| DnsParseError::Truncated {
+                offset: base.unwrap_or(0) + bytes.len(),
+                needed: rdlength - bytes.len(),
+            })?;
+        // fixed size fields have to fit inside rdlength, not just inside the message
+        let u16_at = |i| read_u16(rdata, i, base).map_err(|_| bad_rdata());
+        let u32_at = |i| read_u32(rdata, i, base).map_err(|_| bad_rdata());
+        // names may point anywhere earlier in the message but their own bytes
+        // must end inside the record
+        let name_at = |i: usize| -> ParseResult<(DnsName, usize)> {
+            if i >= rdlength {
+                return Err(bad_rdata());
+            }
+            let (name, len) = read_name(&bytes[i..], message_bytes)?;
+            if i + len > rdlength {
+                return Err(bad_rdata());
+            }
+            Ok((name, i + len))
+        };
+
+        let (data, end) = match rtype {
+            TYPE_A => {
+                let octets: [u8; 4] = rdata.try_into().map_err(|_| bad_rdata())?;
+                (RData::A(Ipv4Addr::from(octets)), rdlength)
+            }
+            TYPE_AAAA => {
+                let octets: [u8; 16] = rdata.try_into().map_err(|_| bad_rdata())?;
+                (RData::Aaaa(Ipv6Addr::from(octets)), rdlength)
+            }
+            TYPE_CNAME => {
+                let (name, end) = name_at(0)?;
+                (RData::Cname(name), end)
+            }
+            TYPE_NS => {
+                let (name, end) = name_at(0)?;
+                (RData::Ns(name), end)
+            }
+            TYPE_PTR => {
+                let (name, end) = name_at(0)?;
+                (RData::Ptr(name), end)
+            }
+            TYPE_DNAME => {
+                let (name, end) = name_at(0)?;
+                (RData::Dname(name), end)
+            }
+            TYPE_MX => {
+                let preference = u16_at(0)?;
+                let (exchange, end) = name_at(2)?;
+                let data = RData::Mx {
+                    preference,
+                    exchange,
+                };
+                (data, end)
+            }
+            TYPE_TXT => {
+                let mut strings = Vec::new();
+                let mut i = 0;
+                while i < rdlength {
+                    let len = rdata[i] as usize;
+                    let string = rdata.get(i + 1..i + 1 + len).ok_or_else(bad_rdata)?;
+                    strings.push(string.to_vec());
+                    i += 1 + len;
+                }
+                (RData::Txt(strings), i)
+            }
+            TYPE_SOA => {
+                let (mname, i) = name_at(0)?;
+                let (rname, i) = name_at(i)?;
+                let data = RData::Soa {
+                    mname,
+                    rname,
+                    serial: u32_at(i)?,
+                    refresh: u32_at(i + 4)?,
+                    retry: u32_at(i + 8)?,
+                    expire: u32_at(i + 12)?,
+                    minimum: u32_at(i + 16)?,
+                };
+                (data, i + 20)
+            }
+            TYPE_SRV => {
+                let priority = u16_at(0)?;
+                let weight = u16_at(2)?;
+                let port = u16_at(4)?;
+                let (target, end) = name_at(6)?;
+                let data = RData::Srv {
+                    priority,
+                    weight,
+                    port,
+                    target,
+                };
+                (data, end)
+            }
+            TYPE_CAA => {
+                let flags = *rdata.first().ok_or_else(bad_rdata)?;
+                let tag_len = *rdata.get(1).ok_or_else(bad_rdata)? as usize;
+                let tag = rdata.get(2..2 + tag_len).ok_or_else(bad_rdata)?;
+                let tag = std::str::from_utf8(tag).map_err(|_| bad_rdata())?;
+                let data = RData::Caa {
+                    flags,
+                    tag: tag.to_string(),
+                    value: rdata[2 + tag_len..].to_vec(),
+                };
+                (data, rdlength)
+            }
+            _ => {
+                let data = RData::Unknown {
+                    rtype,
+                    data: rdata.to_vec(),
+                };
+                (data, rdlength)
+            }
+        };
+        if end != rdlength {
+            return Err(bad_rdata());
+        }
+        Ok(data)
+    }
+}
+
+impl fmt::Display for RData {
+    /// The rdata part of a master file line (RFC 1035 5.1). Anything without a
+    /// text form of its own, or that wouldn't survive being read back, uses the
+    /// generic `\# <length> <hex>` form from RFC 3597 5.
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            RData::A(ip) => write!(f, "{}", ip),
+            RData::Aaaa(ip) => write!(f, "{}", ip),
+            RData::Cname(name) | RData::Ns(name) | RData::Ptr(name) | RData::Dname(name) => {
+                write!(f, "{}", name)
+            }
+            RData::Mx {
+                preference,
+                exchange,
+            } => write!(f, "{} {}", preference, exchange),
+            RData::Txt(strings) if !strings.is_empty() => {
+                for (i, string) in character_strings(strings).enumerate() {
+                    if i > 0 {
+                        f.write_str(" ")?;
+                    }
+                    write_character_string(f, string)?;
+                }
+                Ok(())
+            }
+            RData::Soa {
+                mname,
+                rname,
+                serial,
+                refresh,
+                retry,
+                expire,
+                minimum,
+            } => write!(
+                f,
+                "{} {} {} {} {} {} {}",
+                mname, rname, serial, refresh, retry, expire, minimum
+            ),
+            RData::Srv {
+                priority,
+                weight,
+                port,
+                target,
+            } => write!(f, "{} {} {} {}", priority, weight, port, target),
+            RData::Caa { flags, tag, value }
+                if !tag.is_empty() && tag.bytes().all(|b| b.is_ascii_alphanumeric()) =>
+            {
+                write!(f, "{} {} ", flags, tag)?;
+                write_character_string(f, value)
+            }
+            _ => {
+                let data = match self {
+                    RData::Unknown { data, .. } => data.clone(),
+                    _ => self.to_bytes(),
+                };
+                write!(f, "\\# {}", data.len())?;
+                if !data.is_empty() {
+                    f.write_str(" ")?;
+                }
+                data.iter().try_for_each(|byte| write!(f, "{:02x}", byte))
+            }
+        }
+    }
+}
+
+/// TXT data as the <character-string>s it goes out as, with anything over 255
+/// bytes split into 255 byte pieces.
+fn character_strings(strings: &[Vec<u8>]) -> impl Iterator<Item = &[u8]> {
+    strings.iter().flat_map(|string| {
+        // chunks gives nothing for an empty string, which still takes a length byte
+        (string.chunks(255)).chain(string.is_empty().then_some(&string[..]))
+    })
+}
+
+/// A quoted <character-string>, escaping quotes, backslashes and anything not
+/// printable.
+fn write_character_string(f: &mut fmt::Formatter<'_>, bytes: &[u8]) -> fmt::Result {
+    f.write_str("\"")?;
+    for &byte in bytes {
+        match byte {
+            b'"' | b'\\' => write!(f, "\\{}", byte as char)?,
+            0x20..=0x7E => write!(f, "{}", byte as char)?,
+            _ => write!(f, "\\{:03}", byte)?,
+        }
+    }
+    f.write_str("\"")
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    fn name(s: &str) -> DnsName {
+        s.parse().unwrap()
+    }
+
+    fn round_trip(data: RData) {
+        let bytes = data.to_bytes();
+        let decoded = RData::decode(data.rtype(), &bytes, bytes.len(), &bytes).unwrap();
+        assert_eq!(decoded, data);
+    }
+
+    #[test]
+    fn test_rdata_round_trip() {
+        round_trip(RData::A(Ipv4Addr::new(192, 0, 2, 1)));
+        round_trip(RData::Aaaa("2001:db8::1".parse().unwrap()));
+        round_trip(RData::Cname(name("www.example.com")));
+        round_trip(RData::Ns(name("ns1.example.com")));
+        round_trip(RData::Ptr(name("host.example.com")));
+        round_trip(RData::Dname(name("example.net")));
+        round_trip(RData::Mx {
+            preference: 10,
+            exchange: name("mail.example.com"),
+        });
+        round_trip(RData::Txt(vec![b"v=spf1 -all".to_vec(), Vec::new()]));
+        round_trip(RData::Soa {
+            mname: name("ns1.example.com"),
+            rname: name("hostmaster.example.com"),
+            serial: 2024010101,
+            refresh: 7200,
+            retry: 3600,
+            expire: 1209600,
+            minimum: 300,
+        });
+        round_trip(RData::Srv {
+            priority: 0,
+            weight: 5,
+            port: 5060,
+            target: name("sip.example.com"),
+        });
+        round_trip(RData::Caa {
+            flags: 0,
+            tag: "issue".to_string(),
+            value: b"letsencrypt.org".to_vec(),
+        });
+        round_trip(RData::Unknown {
+            rtype: 65280,
+            data: vec![1, 2, 3],
+        });
+    }
+
+    #[test]
+    fn test_rdata_long_txt_string() {
+        let long = vec![b'a'; 300];
+        let bytes = RData::Txt(vec![long.clone()]).to_bytes();
+        assert_eq!(bytes.len(), 302);
+        assert_eq!((bytes[0], bytes[256]), (255, 45));
+        let decoded = RData::decode(TYPE_TXT, &bytes, bytes.len(), &bytes).unwrap();
+        assert_eq!(
+            decoded,
+            RData::Txt(vec![long[..255].to_vec(), long[255..].to_vec()])
+        );
+        let text = RData::Txt(vec![long]).to_string();
+        assert_eq!(
+            text,
+            format!("\"{}\" \"{}\"", "a".repeat(255), "a".repeat(45))
+        );
+    }
+
+    #[test]
+    fn test_rdata_long_caa_tag() {
+        // the longest tag the length byte can describe
+        let data = RData::Caa {
+            flags: 0,
+            tag: "t".repeat(255),
+            value: b"v".to_vec(),
+        };
+        assert_eq!(data.to_bytes()[1], 255);
+        round_trip(data);
+    }
+
+    #[test]
+    #[should_panic(expected = "CAA tag is longer than 255 bytes")]
+    fn test_rdata_oversized_caa_tag() {
+        let data = RData::Caa {
+            flags: 0,
+            tag: "t".repeat(256),
+            value: b"v".to_vec(),
+        };
+        data.to_bytes();
+    }
+
+    #[test]
+    fn test_type_names() {
+        assert_eq!(type_name(TYPE_AAAA), "AAAA");
+        assert_eq!(type_name(65280), "TYPE65280");
+        assert_eq!(type_from_name("aaaa"), Some(TYPE_AAAA));
+        assert_eq!(type_from_name("type65280"), Some(65280));
+        assert_eq!(type_from_name("TYPE28"), Some(TYPE_AAAA));
+        assert_eq!(type_from_name("TYPE"), None);
+        assert_eq!(type_from_name("BOGUS"), None);
+    }
+
+    #[test]
+    fn test_rdata_decode_compressed_name() {
+        // "example" at offset 0, then an MX record's rdata at offset 9 whose
+        // exchange is "mail" followed by a pointer back to offset 0
+        let message_bytes = vec![
+            0x07,
+            0x65,
+            0x78,
+            0x61,
+            0x6D,
+            0x70,
+            0x6C,
+            0x65,
+            0x00,
+            0x00,
+            0x0A,
+            0x04,
+            0x6D,
+            0x61,
+            0x69,
+            0x6C,
+            0b1100_0000,
+            0x00,
+        ];
+        let data = RData::decode(TYPE_MX, &message_bytes[9..], 9, &message_bytes).unwrap();
+        assert_eq!(
+            data,
+            RData::Mx {
+                preference: 10,
+                exchange: name("mail.example"),
+            }
+        );
+    }
+
+    #[test]
+    fn test_rdata_decode_wrong_length() {
+        let bytes = vec![192, 0, 2, 1, 0];
+        let err = RData::decode(TYPE_A, &bytes, 5, &bytes).unwrap_err();
+        assert_eq!(
+            err,
+            DnsParseError::BadRdata {
+                offset: 0,
+                rtype: TYPE_A
+            }
+        );
+    }
+
+    #[test]
+    fn test_rdata_decode_name_past_rdlength() {
+        // the CNAME target runs on past the 3 bytes rdlength allows
+        let bytes = vec![0x03, 0x77, 0x77, 0x77, 0x00];
+        let err = RData::decode(TYPE_CNAME, &bytes, 3, &bytes).unwrap_err();
+        assert_eq!(
+            err,
+            DnsParseError::BadRdata {
+                offset: 0,
+                rtype: TYPE_CNAME
+            }
+        );
+    }
+}
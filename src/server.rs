@@ -0,0 +1,1618 @@
+//! The UDP and TCP listeners and the query handling they share.
+
+use std::collections::HashSet;
+use std::io::{ErrorKind, Read, Write};
+use std::net::{Ipv4Addr, Ipv6Addr, SocketAddr, TcpListener, TcpStream, UdpSocket};
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::sync::mpsc;
+use std::sync::{Arc, Mutex};
+use std::thread;
+use std::time::{Duration, Instant};
+
+use crate::cache::{Cache, CachedAnswer, QueryFlags};
+use crate::config::ForwardRule;
+use crate::edns::{Edns, SERVER_UDP_PAYLOAD_SIZE};
+use crate::error::UpstreamError;
+use crate::name::DnsName;
+use crate::rdata::{RData, TYPE_A, TYPE_AAAA, TYPE_CNAME};
+use crate::structs::*;
+use crate::upstream::{
+    Strategy, UpstreamPool, UpstreamStats, DEFAULT_UPSTREAM_RETRIES, DEFAULT_UPSTREAM_TIMEOUT,
+};
+use crate::zone::{Lookup, Zone};
+
+/// How long a TCP connection may sit without sending a query before we close it
+/// (RFC 7766 6.2.3 suggests seconds, not minutes).
+const TCP_IDLE_TIMEOUT: Duration = Duration::from_secs(10);
+/// Most TCP connections we'll serve at once; past this new ones are closed
+/// straight away.
+const MAX_TCP_CONNECTIONS: usize = 64;
+
+/// Most CNAMEs we'll follow for one question before giving up with SERVFAIL.
+const MAX_CNAME_CHAIN: usize = 8;
+/// Most questions in one query we'll forward at once, each on its own thread.
+/// A query needing more than this is REFUSED rather than fanned out.
+const MAX_FORWARDED_QUESTIONS: usize = 16;
+
+/// Default cap on the answer cache, in (estimated) bytes.
+pub const DEFAULT_CACHE_SIZE: usize = 4 * 1024 * 1024;
+/// Threads answering UDP queries. Most of their time goes on waiting for the
+/// upstream, so this is well past the number of cores.
+pub const DEFAULT_WORKERS: usize = 16;
+/// UDP queries being answered or waiting for a worker before we stop reading
+/// more off the socket.
+pub const DEFAULT_MAX_IN_FLIGHT: usize = 256;
+
+#[derive(Debug, Clone)]
+pub struct ServerConfig {
+    /// Zones we answer for ourselves; questions inside them never go upstream.
+    pub zones: Vec<Zone>,
+    /// Resolvers to forward to when no zone or rule in `forward_rules`
+    /// matches. With none, those questions get made up answers, or REFUSED if
+    /// there are zones.
+    pub resolvers: Vec<String>,
+    /// Resolvers for particular domains, picked by longest matching suffix.
+    pub forward_rules: Vec<ForwardRule>,
+    /// How to pick between the resolvers.
+    pub upstream_strategy: Strategy,
+    /// Most bytes of forwarded answers to keep cached; zero disables the cache.
+    pub cache_size: usize,
+    /// How long to wait for the resolver each time we ask it.
+    pub upstream_timeout: Duration,
+    /// How many times to ask again before giving up with SERVFAIL.
+    pub upstream_retries: u32,
+    /// Threads answering UDP queries.
+    pub workers: usize,
+    /// Most UDP queries accepted but not yet answered.
+    pub max_in_flight: usize,
+}
+
+impl Default for ServerConfig {
+    fn default() -> Self {
+        ServerConfig {
+            zones: Vec::new(),
+            resolvers: Vec::new(),
+            forward_rules: Vec::new(),
+            upstream_strategy: Strategy::default(),
+            cache_size: DEFAULT_CACHE_SIZE,
+            upstream_timeout: DEFAULT_UPSTREAM_TIMEOUT,
+            upstream_retries: DEFAULT_UPSTREAM_RETRIES,
+            workers: DEFAULT_WORKERS,
+            max_in_flight: DEFAULT_MAX_IN_FLIGHT,
+        }
+    }
+}
+
+/// The configuration plus whatever state is shared between all the listeners.
+#[derive(Debug)]
+pub struct Server {
+    pub config: ServerConfig,
+    cache: Mutex<Cache>,
+    upstreams: UpstreamPool,
+    // longest suffix first
+    forward_rules: Vec<(DnsName, UpstreamPool)>,
+    // longest origin first
+    zones: Vec<Zone>,
+}
+
+impl Server {
+    pub fn new(config: ServerConfig) -> Server {
+        let cache = Mutex::new(Cache::new(config.cache_size));
+        let upstreams = UpstreamPool::new(&config.resolvers, config.upstream_strategy);
+        let mut forward_rules: Vec<(DnsName, UpstreamPool)> = (config.forward_rules.iter())
+            .map(|rule| {
+                let pool = UpstreamPool::new(&rule.upstreams, config.upstream_strategy);
+                (rule.suffix.clone(), pool)
+            })
+            .collect();
+        forward_rules.sort_by_key(|(suffix, _)| std::cmp::Reverse(suffix.label_count()));
+        let mut zones = config.zones.clone();
+        zones.sort_by_key(|zone| std::cmp::Reverse(zone.origin().label_count()));
+        Server {
+            config,
+            cache,
+            upstreams,
+            forward_rules,
+            zones,
+        }
+    }
+
+    /// Health and traffic counters for each resolver we forward to.
+    pub fn upstream_stats(&self) -> Vec<UpstreamStats> {
+        let rule_pools = self.forward_rules.iter().map(|(_, pool)| pool);
+        (rule_pools.chain([&self.upstreams]))
+            .flat_map(UpstreamPool::stats)
+            .collect()
+    }
+
+    /// The most specific of our zones that `qname` is in.
+    fn zone_for(&self, qname: &DnsName) -> Option<&Zone> {
+        (self.zones.iter()).find(|zone| qname.is_subdomain_of(zone.origin()))
+    }
+
+    /// Whether we'll forward anything at all, which is what RA promises.
+    fn can_recurse(&self) -> bool {
+        !self.upstreams.is_empty() || !self.forward_rules.is_empty()
+    }
+
+    /// The resolvers for `qname`: the forwarding rule with the longest matching
+    /// suffix, or else the default ones if there are any.
+    fn upstreams_for(&self, qname: &DnsName) -> Option<&UpstreamPool> {
+        let rule = (self.forward_rules.iter()).find(|(suffix, _)| qname.is_subdomain_of(suffix));
+        match rule {
+            Some((_, pool)) => Some(pool),
+            None if !self.upstreams.is_empty() => Some(&self.upstreams),
+            None => None,
+        }
+    }
+}
+
+/// Which transport a query came in on, since that decides how big the reply can be.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Transport {
+    Udp,
+    Tcp,
+}
+
+/// Reads queries off the socket and hands them to a pool of worker threads, so
+/// a slow upstream only holds up the queries waiting on it. Once
+/// `max_in_flight` queries are queued or being answered we stop reading until a
+/// worker frees up, and anything past that waits in (or is dropped by) the
+/// kernel's socket buffer.
+pub fn serve_udp(udp_socket: UdpSocket, server: Arc<Server>) {
+    let workers = server.config.workers.max(1);
+    let queue_len = server.config.max_in_flight.saturating_sub(workers);
+    let (sender, receiver) = mpsc::sync_channel::<(Vec<u8>, SocketAddr)>(queue_len);
+    let receiver = Arc::new(Mutex::new(receiver));
+    for _ in 0..workers {
+        let socket = udp_socket.try_clone().expect("Failed to clone UDP socket");
+        let receiver = Arc::clone(&receiver);
+        let server = Arc::clone(&server);
+        thread::spawn(move || loop {
+            // the lock is only held while waiting for the next query
+            let query = receiver.lock().unwrap().recv();
+            let Ok((request, source)) = query else {
+                break;
+            };
+            if let Some(reply) = handle_query(&request, Transport::Udp, &server) {
+                if let Err(e) = socket.send_to(&reply, source) {
+                    eprintln!("Error sending response to {}: {}", source, e);
+                }
+            }
+        });
+    }
+
+    // big enough for anything a client could send when we advertise our own size
+    let mut buf = [0; SERVER_UDP_PAYLOAD_SIZE as usize];
+    loop {
+        match udp_socket.recv_from(&mut buf) {
+            Ok((size, source)) => {
+                // blocks while the workers are all busy and the queue is full
+                if sender.send((buf[..size].to_vec(), source)).is_err() {
+                    break;
+                }
+            }
+            Err(e) => {
+                eprintln!("Error receiving data: {}", e);
+                break;
+            }
+        }
+    }
+}
+
+pub fn serve_tcp(listener: TcpListener, server: Arc<Server>) {
+    let open_connections = Arc::new(AtomicUsize::new(0));
+    for stream in listener.incoming() {
+        let stream = match stream {
+            Ok(stream) => stream,
+            Err(e) => {
+                eprintln!("Error accepting TCP connection: {}", e);
+                continue;
+            }
+        };
+        if open_connections.fetch_add(1, Ordering::SeqCst) >= MAX_TCP_CONNECTIONS {
+            open_connections.fetch_sub(1, Ordering::SeqCst);
+            // dropping the stream closes it, the client can try again later
+            continue;
+        }
+        let server = Arc::clone(&server);
+        let open_connections = Arc::clone(&open_connections);
+        thread::spawn(move || {
+            if let Err(e) = serve_tcp_connection(stream, &server) {
+                eprintln!("Error on TCP connection: {}", e);
+            }
+            open_connections.fetch_sub(1, Ordering::SeqCst);
+        });
+    }
+}
+
+/// Answers length-prefixed queries (RFC 1035 4.2.2) on one connection until the
+/// client closes it or goes quiet. Clients may pipeline several queries without
+/// waiting (RFC 7766 6.2.1.1); we answer them in the order they arrive.
+fn serve_tcp_connection(mut stream: TcpStream, server: &Server) -> std::io::Result<()> {
+    stream.set_read_timeout(Some(TCP_IDLE_TIMEOUT))?;
+    // a client that sends queries but never reads the replies would otherwise
+    // block a write, and hold on to its connection slot, forever
+    stream.set_write_timeout(Some(TCP_IDLE_TIMEOUT))?;
+    loop {
+        let mut len_bytes = [0; 2];
+        match stream.read_exact(&mut len_bytes) {
+            Ok(()) => {}
+            // a clean close or an idle timeout between queries just ends the connection
+            Err(e)
+                if matches!(
+                    e.kind(),
+                    ErrorKind::UnexpectedEof | ErrorKind::WouldBlock | ErrorKind::TimedOut
+                ) =>
+            {
+                return Ok(())
+            }
+            Err(e) => return Err(e),
+        }
+        let mut request = vec![0; u16::from_be_bytes(len_bytes) as usize];
+        stream.read_exact(&mut request)?;
+        if let Some(reply) = handle_query(&request, Transport::Tcp, server) {
+            let mut framed = Vec::with_capacity(reply.len() + 2);
+            framed.extend_from_slice(&(reply.len() as u16).to_be_bytes());
+            framed.extend_from_slice(&reply);
+            stream.write_all(&framed)?;
+        }
+    }
+}
+
+/// Builds the reply to one query, whichever transport it came in on. Returns None
+/// when the query is too broken to answer at all, or is a response itself:
+/// answering those would let two servers, or one spoofed packet, set off an
+/// endless exchange.
+pub fn handle_query(request: &[u8], transport: Transport, server: &Server) -> Option<Vec<u8>> {
+    let incoming_msg = match DnsMessage::from_bytes(request) {
+        Ok(msg) if msg.header.response => return None,
+        Ok(msg) => msg,
+        Err(e) => {
+            // without a full header we don't even have an id to reply to
+            let header = DnsHeader::from_bytes(request).ok()?;
+            if header.response {
+                return None;
+            }
+            eprintln!("Malformed query: {}", e);
+            return Some(formerr_reply(&header).to_bytes());
+        }
+    };
+    let mut reply_message = DnsMessage::reply_to(&incoming_msg);
+    if incoming_msg.header.opcode != 0 {
+        // only standard queries, nothing gets looked up or forwarded for the rest
+        reply_message.set_rcode(RCODE_NOTIMP);
+        return Some(reply_message.to_bytes());
+    }
+    if incoming_msg
+        .edns
+        .as_ref()
+        .is_some_and(|edns| edns.version > 0)
+    {
+        // we only speak EDNS version 0
+        reply_message.set_rcode(RCODE_BADVERS);
+        return Some(reply_message.to_bytes());
+    }
+    let mut answers = Vec::new();
+    let mut authorities = Vec::new();
+    let mut additionals = Vec::new();
+    let mut status = ReplyStatus::default();
+    // a question asked more than once is only looked up and answered once
+    let mut seen = HashSet::new();
+    let questions: Vec<&DnsQuestion> = (incoming_msg.questions.iter())
+        .filter(|question| seen.insert(*question))
+        .collect();
+    // questions in one of our zones are answered from it, and the rest get
+    // forwarded if there's anywhere to forward them to
+    let zones: Vec<Option<&Zone>> = (questions.iter())
+        .map(|question| server.zone_for(&question.qname))
+        .collect();
+    let pools: Vec<Option<&UpstreamPool>> = (questions.iter().zip(&zones))
+        .map(|(question, zone)| match zone {
+            Some(_) => None,
+            None => server.upstreams_for(&question.qname),
+        })
+        .collect();
+    let cached: Vec<Option<CachedAnswer>> = {
+        let mut cache = server.cache.lock().unwrap();
+        let flags = QueryFlags::of(&incoming_msg);
+        let now = Instant::now();
+        (questions.iter().zip(&pools))
+            .map(|(question, pool)| pool.and_then(|_| cache.get(question, flags, now)))
+            .collect()
+    };
+    let misses = (pools.iter().zip(&cached))
+        .filter(|(pool, cached)| pool.is_some() && cached.is_none())
+        .count();
+    if misses > MAX_FORWARDED_QUESTIONS {
+        reply_message.set_rcode(RCODE_REFUSED);
+        return Some(reply_message.to_bytes());
+    }
+    // the resolver will only respond to one question at a time, so every
+    // question that missed the cache goes out as its own query, all at once,
+    // and they share one deadline
+    let attempts = server.config.upstream_retries + 1;
+    let deadline = Instant::now() + server.config.upstream_timeout * attempts;
+    let forwarded: Vec<Option<Result<DnsMessage, UpstreamError>>> = thread::scope(|scope| {
+        let lookups: Vec<_> =
+            (questions.iter().zip(&pools).zip(&cached))
+                .map(|((question, pool), cached)| match (pool, cached) {
+                    (Some(pool), None) => Some(scope.spawn(|| {
+                        forward_question(server, pool, &incoming_msg, question, deadline)
+                    })),
+                    _ => None,
+                })
+                .collect();
+        (lookups.into_iter())
+            .map(|lookup| lookup.map(|handle| handle.join().unwrap()))
+            .collect()
+    });
+
+    // put the answers together in question order
+    for ((((question, zone), pool), cached), forwarded) in (questions.iter().copied())
+        .zip(&zones)
+        .zip(&pools)
+        .zip(cached)
+        .zip(forwarded)
+    {
+        let first_answer = answers.len();
+        if let Some(zone) = zone {
+            let lookup = zone.lookup(&question.qname, question.qtype);
+            if let Lookup::Referral { ns, glue } = lookup {
+                // the child zone's servers have the answer, not us
+                authorities.extend(ns);
+                additionals.extend(glue);
+                status.add_referral(server.can_recurse());
+            } else {
+                let rcode = answer_from_zone(zone, lookup, &mut answers, &mut authorities);
+                status.add_authoritative(rcode, server.can_recurse());
+            }
+        } else {
+            match (pool, cached, forwarded) {
+                (None, _, _) if !server.zones.is_empty() => {
+                    // we're authoritative for some zones and this isn't in any of them
+                    status.add_local(RCODE_REFUSED);
+                }
+                (None, _, _) => {
+                    // each question gets its own answer, so counts come from what we emit
+                    answers.extend(make_answer(question));
+                }
+                (_, Some(CachedAnswer::Records(records)), _) => {
+                    status.add_cached(RCODE_NOERROR);
+                    answers.extend(records);
+                }
+                (_, Some(CachedAnswer::Negative { rcode, soa }), _) => {
+                    status.add_cached(rcode);
+                    authorities.push(soa);
+                }
+                (_, None, Some(Ok(resolver_msg))) => {
+                    cache_response(server, &incoming_msg, question, &resolver_msg);
+                    status.add_response(&resolver_msg);
+                    // extract the records from the resolver response
+                    // and add them to the response we will send back to the client
+                    answers.extend(resolver_msg.answers);
+                    authorities.extend(resolver_msg.authorities);
+                    additionals.extend(resolver_msg.additionals);
+                }
+                (_, None, Some(Err(e))) => {
+                    eprintln!("No answer from resolver for {}: {}", question.qname, e);
+                    status.add_failure();
+                }
+                (_, None, None) => unreachable!("every cache miss is forwarded"),
+            }
+        }
+        let chased = chase_aliases(
+            server,
+            &incoming_msg,
+            question,
+            &mut answers,
+            first_answer,
+            &mut authorities,
+            deadline,
+        );
+        if let Some(rcode) = chased {
+            status.merge_rcode(rcode);
+        }
+    }
+    let max_size = match transport {
+        Transport::Udp => incoming_msg.max_udp_payload_size(),
+        Transport::Tcp => u16::MAX as usize,
+    };
+    reply_message.answers = answers;
+    reply_message.authorities = authorities;
+    reply_message.additionals = additionals;
+    if status.responses > 0 {
+        status.apply_to(&mut reply_message);
+    }
+    // if it's too big for the client this sets TC, and it'll retry over TCP
+    let reply_bytes = reply_message.truncate_to(max_size);
+    Some(reply_bytes)
+}
+
+/// Follows the CNAMEs among a question's answers, `answers[first..]`, to records
+/// of the type asked for. When the chain runs off the end of what we've got
+/// the next name is looked up in our zones, or upstream if we're forwarding
+/// it, and whatever comes back is added on. Returns the rcode for the end of
+/// the chain if we had to go looking, or if the chain is a loop or too long to
+/// follow. It stops as soon as a lookup brings back nothing that takes the
+/// chain any further.
+fn chase_aliases(
+    server: &Server,
+    incoming_msg: &DnsMessage,
+    question: &DnsQuestion,
+    answers: &mut Vec<DnsAnswer>,
+    first: usize,
+    authorities: &mut Vec<DnsAnswer>,
+    deadline: Instant,
+) -> Option<u16> {
+    if question.qtype == TYPE_CNAME {
+        return None;
+    }
+    let mut chain = vec![question.qname.clone()];
+    let mut rcode = None;
+    let mut lookups = 0;
+    loop {
+        let name = chain.last().expect("starts with the qname");
+        let records = &answers[first..];
+        if (records.iter()).any(|record| record.name == *name && record.qtype == question.qtype) {
+            return rcode;
+        }
+        let target = records.iter().find_map(|record| match &record.rdata {
+            RData::Cname(target) if record.name == *name => Some(target.clone()),
+            _ => None,
+        });
+        if let Some(target) = target {
+            if chain.contains(&target) || chain.len() > MAX_CNAME_CHAIN {
+                eprintln!("Gave up following CNAMEs from {}", question.qname);
+                return Some(RCODE_SERVFAIL);
+            }
+            chain.push(target);
+            continue;
+        }
+        if chain.len() == 1 {
+            // not an alias, so there's nothing to follow
+            return rcode;
+        }
+        if lookups == MAX_CNAME_CHAIN {
+            eprintln!("Gave up following CNAMEs from {}", question.qname);
+            return Some(RCODE_SERVFAIL);
+        }
+        lookups += 1;
+        let next = DnsQuestion {
+            qname: name.clone(),
+            ..question.clone()
+        };
+        let (next_rcode, next_answers, next_authorities) =
+            look_up_alias_target(server, incoming_msg, &next, deadline)?;
+        rcode = Some(next_rcode);
+        if next_answers.is_empty() {
+            // the negative answer is about the last name in the chain (RFC 6604)
+            authorities.extend(next_authorities);
+            return rcode;
+        }
+        // only the type asked for or another CNAME at this name carry on the
+        // chain; anything else (a DNAME, or other types for ANY) is kept, but
+        // asking again would just bring back the same records
+        let extends = (next_answers.iter()).any(|record| {
+            record.name == *name
+                && (record.qtype == question.qtype || matches!(record.rdata, RData::Cname(_)))
+        });
+        answers.extend(next_answers);
+        if !extends {
+            return rcode;
+        }
+    }
+}
+
+/// Answers a question that an alias pointed us at, as (rcode, answers,
+/// authorities). None if it isn't in our zones and there's nowhere to forward
+/// it, so the client has to finish the chain itself.
+fn look_up_alias_target(
+    server: &Server,
+    incoming_msg: &DnsMessage,
+    question: &DnsQuestion,
+    deadline: Instant,
+) -> Option<(u16, Vec<DnsAnswer>, Vec<DnsAnswer>)> {
+    let mut answers = Vec::new();
+    let mut authorities = Vec::new();
+    if let Some(zone) = server.zone_for(&question.qname) {
+        let lookup = zone.lookup(&question.qname, question.qtype);
+        // a delegated name is no more ours than any other
+        if !matches!(lookup, Lookup::Referral { .. }) {
+            let rcode = answer_from_zone(zone, lookup, &mut answers, &mut authorities);
+            return Some((rcode, answers, authorities));
+        }
+    }
+    let pool = server.upstreams_for(&question.qname)?;
+    let flags = QueryFlags::of(incoming_msg);
+    let cached = (server.cache.lock().unwrap()).get(question, flags, Instant::now());
+    match cached {
+        Some(CachedAnswer::Records(records)) => Some((RCODE_NOERROR, records, authorities)),
+        Some(CachedAnswer::Negative { rcode, soa }) => Some((rcode, answers, vec![soa])),
+        None => match forward_question(server, pool, incoming_msg, question, deadline) {
+            Ok(resolver_msg) => {
+                cache_response(server, incoming_msg, question, &resolver_msg);
+                let rcode = resolver_msg.rcode();
+                Some((rcode, resolver_msg.answers, resolver_msg.authorities))
+            }
+            Err(e) => {
+                eprintln!("No answer from resolver for {}: {}", question.qname, e);
+                Some((RCODE_SERVFAIL, answers, authorities))
+            }
+        },
+    }
+}
+
+/// Caches an upstream's response to a question the client asked, keyed on the
+/// client's DO and CD bits since they went upstream with it.
+fn cache_response(
+    server: &Server,
+    incoming_msg: &DnsMessage,
+    question: &DnsQuestion,
+    response: &DnsMessage,
+) {
+    if let Some(answer) = CachedAnswer::from_response(response) {
+        let flags = QueryFlags::of(incoming_msg);
+        (server.cache.lock().unwrap()).insert(question, flags, answer, Instant::now());
+    }
+}
+
+/// Puts what one of our zones had for a question into the reply, returning the
+/// rcode. Negative answers carry the zone's SOA so they can be cached (RFC 2308
+/// 3). Referrals aren't answers, so the caller deals with those.
+fn answer_from_zone(
+    zone: &Zone,
+    lookup: Lookup,
+    answers: &mut Vec<DnsAnswer>,
+    authorities: &mut Vec<DnsAnswer>,
+) -> u16 {
+    match lookup {
+        Lookup::Answer(records) => {
+            answers.extend(records);
+            RCODE_NOERROR
+        }
+        Lookup::NoData => {
+            authorities.push(zone.negative_soa());
+            RCODE_NOERROR
+        }
+        Lookup::NxDomain => {
+            authorities.push(zone.negative_soa());
+            RCODE_NXDOMAIN
+        }
+        Lookup::YxDomain(dname) => {
+            answers.extend(dname);
+            RCODE_YXDOMAIN
+        }
+        Lookup::Referral { .. } => unreachable!("referrals are handled by the caller"),
+    }
+}
+
+/// Asks the resolvers one of the client's questions, giving up at `deadline`.
+fn forward_question(
+    server: &Server,
+    pool: &UpstreamPool,
+    incoming_msg: &DnsMessage,
+    question: &DnsQuestion,
+    deadline: Instant,
+) -> Result<DnsMessage, UpstreamError> {
+    // it goes upstream with a random id and comes back with this one
+    let mut forwarded_message = DnsMessage::query(incoming_msg.header.id, question.clone());
+    forwarded_message.header.recursion_desired = incoming_msg.header.recursion_desired;
+    forwarded_message.header.checking_disabled = incoming_msg.header.checking_disabled;
+    // advertise our buffer size so the resolver doesn't truncate for us, and
+    // pass on whether the client wants DNSSEC records
+    forwarded_message.edns = Some(Edns {
+        dnssec_ok: (incoming_msg.edns.as_ref()).is_some_and(|edns| edns.dnssec_ok),
+        ..Edns::default()
+    });
+    let reply = pool.query(
+        &forwarded_message,
+        server.config.upstream_timeout,
+        server.config.upstream_retries,
+        deadline,
+    )?;
+    let rcode = reply.message.rcode();
+    if matches!(rcode, RCODE_SERVFAIL | RCODE_REFUSED) {
+        // the pool only settles for this when nobody did better
+        eprintln!(
+            "Passing on rcode {} for {} from upstream {}",
+            rcode, question.qname, reply.upstream
+        );
+    }
+    Ok(reply.message)
+}
+
+/// The rcode and header bits for a reply, merged from the answers to each of
+/// its questions, whether they came from upstream, the cache or our zones.
+#[derive(Debug, Default)]
+struct ReplyStatus {
+    rcode: u16,
+    responses: usize,
+    authoritative: usize,
+    authenticated: usize,
+    recursion_available: usize,
+}
+
+impl ReplyStatus {
+    fn add_response(&mut self, response: &DnsMessage) {
+        self.merge_rcode(response.rcode());
+        self.responses += 1;
+        self.authoritative += response.header.authoritative_answer as usize;
+        self.authenticated += response.header.authed_data as usize;
+        self.recursion_available += response.header.recursion_available as usize;
+    }
+
+    /// An answer from our own cache: never authoritative (RFC 1035 6.1), and
+    /// we don't keep track of whether it was validated.
+    fn add_cached(&mut self, rcode: u16) {
+        self.merge_rcode(rcode);
+        self.responses += 1;
+        self.recursion_available += 1;
+    }
+
+    /// An answer from one of our zones. RA says whether we'd recurse for the
+    /// client, which we can't if we've nowhere to forward to.
+    fn add_authoritative(&mut self, rcode: u16, recursion_available: bool) {
+        self.merge_rcode(rcode);
+        self.responses += 1;
+        self.authoritative += 1;
+        self.recursion_available += recursion_available as usize;
+    }
+
+    /// A referral to a zone delegated from one of ours. It isn't an answer, so
+    /// it's never authoritative (RFC 1034 4.3.2).
+    fn add_referral(&mut self, recursion_available: bool) {
+        self.merge_rcode(RCODE_NOERROR);
+        self.responses += 1;
+        self.recursion_available += recursion_available as usize;
+    }
+
+    /// A question we answered without any data behind it.
+    fn add_local(&mut self, rcode: u16) {
+        self.merge_rcode(rcode);
+        self.responses += 1;
+    }
+
+    /// A question we couldn't get any usable answer to.
+    fn add_failure(&mut self) {
+        self.merge_rcode(RCODE_SERVFAIL);
+        self.responses += 1;
+    }
+
+    /// Keeps the most serious rcode: any failure beats NXDOMAIN, which beats
+    /// NOERROR. Between two failures the first one wins.
+    fn merge_rcode(&mut self, rcode: u16) {
+        let severity = |rcode| match rcode {
+            RCODE_NOERROR => 0,
+            RCODE_NXDOMAIN => 1,
+            _ => 2,
+        };
+        if severity(rcode) > severity(self.rcode) {
+            self.rcode = rcode;
+        }
+    }
+
+    /// AA, AD and RA are only set when every part of the reply earned them.
+    fn apply_to(&self, reply: &mut DnsMessage) {
+        let all = |count| self.responses > 0 && count == self.responses;
+        if reply.rcode() == RCODE_NOERROR {
+            reply.set_rcode(self.rcode);
+        }
+        reply.header.authoritative_answer = all(self.authoritative);
+        reply.header.authed_data = all(self.authenticated);
+        reply.header.recursion_available = all(self.recursion_available);
+    }
+}
+
+/// The stub's made up answer to a question. Only address types get one; for
+/// anything else the name exists but has no data of that type.
+fn make_answer(question: &DnsQuestion) -> Option<DnsAnswer> {
+    let rdata = match question.qtype {
+        TYPE_A => RData::A(Ipv4Addr::new(8, 8, 8, 8)),
+        TYPE_AAAA => RData::Aaaa(Ipv6Addr::new(0x2001, 0x4860, 0x4860, 0, 0, 0, 0, 0x8888)),
+        _ => return None,
+    };
+    Some(DnsAnswer::new(question.qname.clone(), 60, rdata))
+}
+
+/// FORMERR reply for a query we couldn't parse, echoing back just enough of the
+/// header for the client to match it up.
+fn formerr_reply(header: &DnsHeader) -> DnsMessage {
+    DnsMessage {
+        header: DnsHeader {
+            id: header.id,
+            opcode: header.opcode,
+            recursion_desired: header.recursion_desired,
+            rescode: RCODE_FORMERR as u8,
+            response: true,
+            ..DnsHeader::default()
+        },
+        ..DnsMessage::default()
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::rdata::TYPE_DNAME;
+
+    fn query_bytes(id: u16, qname: &str) -> Vec<u8> {
+        DnsMessage {
+            header: DnsHeader {
+                id,
+                response: false,
+                ..DnsHeader::default()
+            },
+            questions: vec![DnsQuestion {
+                qname: qname.parse().unwrap(),
+                qtype: 1,
+                qclass: 1,
+            }],
+            answers: Vec::new(),
+            authorities: Vec::new(),
+            additionals: Vec::new(),
+            edns: None,
+        }
+        .to_bytes()
+    }
+
+    fn start_tcp_server() -> SocketAddr {
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let addr = listener.local_addr().unwrap();
+        thread::spawn(move || serve_tcp(listener, Arc::new(Server::new(ServerConfig::default()))));
+        addr
+    }
+
+    fn read_framed(stream: &mut TcpStream) -> DnsMessage {
+        let mut len_bytes = [0; 2];
+        stream.read_exact(&mut len_bytes).unwrap();
+        let mut reply = vec![0; u16::from_be_bytes(len_bytes) as usize];
+        stream.read_exact(&mut reply).unwrap();
+        DnsMessage::from_bytes(&reply).unwrap()
+    }
+
+    #[test]
+    fn test_tcp_pipelined_queries() {
+        let mut stream = TcpStream::connect(start_tcp_server()).unwrap();
+        // send both queries before reading anything back, in a single write
+        let mut framed = Vec::new();
+        for (id, qname) in [(1, "a.example.com"), (2, "b.example.com")] {
+            let query = query_bytes(id, qname);
+            framed.extend_from_slice(&(query.len() as u16).to_be_bytes());
+            framed.extend_from_slice(&query);
+        }
+        stream.write_all(&framed).unwrap();
+        let first = read_framed(&mut stream);
+        let second = read_framed(&mut stream);
+        assert_eq!(first.header.id, 1);
+        assert_eq!(first.answers[0].name, "a.example.com".parse().unwrap());
+        assert_eq!(second.header.id, 2);
+        assert_eq!(second.answers[0].name, "b.example.com".parse().unwrap());
+    }
+
+    #[test]
+    fn test_tcp_malformed_query_gets_formerr() {
+        let mut stream = TcpStream::connect(start_tcp_server()).unwrap();
+        // a header claiming a question that isn't there
+        let query = [0x12, 0x34, 0x01, 0x00, 0x00, 0x01, 0, 0, 0, 0, 0, 0];
+        stream.write_all(&[0, 12]).unwrap();
+        stream.write_all(&query).unwrap();
+        let reply = read_framed(&mut stream);
+        assert_eq!(reply.header.id, 0x1234);
+        assert_eq!(reply.header.rescode, 1);
+    }
+
+    #[test]
+    fn test_responses_are_dropped() {
+        let server = Server::new(ServerConfig::default());
+        let mut response = DnsMessage::from_bytes(&query_bytes(1, "a.example.com")).unwrap();
+        response.header.response = true;
+        assert_eq!(
+            handle_query(&response.to_bytes(), Transport::Udp, &server),
+            None
+        );
+        // not even a FORMERR for a broken one
+        let broken = [0x12, 0x34, 0x81, 0x00, 0x00, 0x01, 0, 0, 0, 0, 0, 0];
+        assert_eq!(handle_query(&broken, Transport::Udp, &server), None);
+        let broken_query = [0x12, 0x34, 0x01, 0x00, 0x00, 0x01, 0, 0, 0, 0, 0, 0];
+        assert!(handle_query(&broken_query, Transport::Udp, &server).is_some());
+    }
+
+    #[test]
+    fn test_oversized_udp_reply_sets_tc() {
+        // 40 questions for names next to each other fit in a small query, but
+        // the stub answers every one of them and that doesn't fit in 512 bytes
+        let questions = (0..40)
+            .map(|i| DnsQuestion::new(format!("a{}.example.com", i).parse().unwrap(), 1))
+            .collect();
+        let query = DnsMessage {
+            header: DnsHeader {
+                response: false,
+                ..DnsHeader::default()
+            },
+            questions,
+            answers: Vec::new(),
+            authorities: Vec::new(),
+            additionals: Vec::new(),
+            edns: None,
+        }
+        .to_bytes();
+        assert!(query.len() <= 512);
+        let server = Server::new(ServerConfig::default());
+
+        let udp_reply = handle_query(&query, Transport::Udp, &server).unwrap();
+        assert!(udp_reply.len() <= 512);
+        let udp_reply = DnsMessage::from_bytes(&udp_reply).unwrap();
+        assert!(udp_reply.header.truncated_message);
+        // whole RRsets are dropped from the end, so only the first few make it
+        assert!(udp_reply.answers.len() < 40);
+
+        // the same query over TCP gets everything
+        let tcp_reply = handle_query(&query, Transport::Tcp, &server).unwrap();
+        let tcp_reply = DnsMessage::from_bytes(&tcp_reply).unwrap();
+        assert!(!tcp_reply.header.truncated_message);
+        assert_eq!(tcp_reply.answers.len(), 40);
+    }
+
+    /// A resolver that answers every A query with one record, except for names
+    /// under missing.example.com which don't exist and refused.example.com which
+    /// it won't answer, and counts how many queries it has seen.
+    fn start_fake_resolver() -> (SocketAddr, Arc<AtomicUsize>) {
+        let socket = UdpSocket::bind("127.0.0.1:0").unwrap();
+        let addr = socket.local_addr().unwrap();
+        let queries = Arc::new(AtomicUsize::new(0));
+        let counter = Arc::clone(&queries);
+        thread::spawn(move || {
+            let mut buf = [0; 512];
+            while let Ok((size, source)) = socket.recv_from(&mut buf) {
+                counter.fetch_add(1, Ordering::SeqCst);
+                let query = DnsMessage::from_bytes(&buf[..size]).unwrap();
+                let mut reply = DnsMessage::reply_to(&query);
+                reply.header.recursion_available = true;
+                let qname = &query.questions[0].qname;
+                if qname.is_subdomain_of(&"refused.example.com".parse().unwrap()) {
+                    reply.set_rcode(RCODE_REFUSED);
+                } else if qname.is_subdomain_of(&"alias.example.com".parse().unwrap()) {
+                    // an alias, without the records it points to
+                    reply.answers.push(DnsAnswer::new(
+                        qname.clone(),
+                        300,
+                        RData::Cname("target.example.org".parse().unwrap()),
+                    ));
+                } else if qname.is_subdomain_of(&"missing.example.com".parse().unwrap()) {
+                    reply.set_rcode(RCODE_NXDOMAIN);
+                    reply.authorities.push(DnsAnswer::new(
+                        "example.com".parse().unwrap(),
+                        3600,
+                        RData::Soa {
+                            mname: "ns1.example.com".parse().unwrap(),
+                            rname: "hostmaster.example.com".parse().unwrap(),
+                            serial: 1,
+                            refresh: 7200,
+                            retry: 3600,
+                            expire: 1209600,
+                            minimum: 300,
+                        },
+                    ));
+                } else {
+                    reply.answers.push(DnsAnswer::new(
+                        qname.clone(),
+                        300,
+                        RData::A(Ipv4Addr::new(192, 0, 2, 1)),
+                    ));
+                }
+                socket.send_to(&reply.to_bytes(), source).unwrap();
+            }
+        });
+        (addr, queries)
+    }
+
+    #[test]
+    fn test_forwarded_answers_are_cached() {
+        let (resolver, queries) = start_fake_resolver();
+        let server = Server::new(ServerConfig {
+            resolvers: vec![resolver.to_string()],
+            ..ServerConfig::default()
+        });
+        for id in [1, 2] {
+            let reply =
+                handle_query(&query_bytes(id, "a.example.com"), Transport::Udp, &server).unwrap();
+            let reply = DnsMessage::from_bytes(&reply).unwrap();
+            assert_eq!(reply.header.id, id);
+            assert_eq!(reply.answers.len(), 1);
+            assert!(reply.answers[0].ttl <= 300);
+        }
+        assert_eq!(queries.load(Ordering::SeqCst), 1);
+
+        // with the cache turned off every query goes upstream
+        let server = Server::new(ServerConfig {
+            resolvers: vec![resolver.to_string()],
+            cache_size: 0,
+            ..ServerConfig::default()
+        });
+        for id in [3, 4] {
+            handle_query(&query_bytes(id, "a.example.com"), Transport::Udp, &server).unwrap();
+        }
+        assert_eq!(queries.load(Ordering::SeqCst), 3);
+    }
+
+    #[test]
+    fn test_cache_depends_on_do_bit() {
+        let (resolver, queries) = start_fake_resolver();
+        let server = Server::new(ServerConfig {
+            resolvers: vec![resolver.to_string()],
+            ..ServerConfig::default()
+        });
+        let query = |dnssec_ok| {
+            let mut query =
+                DnsMessage::query(4, DnsQuestion::new("a.example.com".parse().unwrap(), 1));
+            query.edns = Some(Edns {
+                dnssec_ok,
+                ..Edns::default()
+            });
+            query.to_bytes()
+        };
+        for dnssec_ok in [true, false, true, false] {
+            handle_query(&query(dnssec_ok), Transport::Udp, &server).unwrap();
+        }
+        // one upstream query for each setting, the repeats come from the cache
+        assert_eq!(queries.load(Ordering::SeqCst), 2);
+    }
+
+    #[test]
+    fn test_nxdomain_is_cached() {
+        let (resolver, queries) = start_fake_resolver();
+        let server = Server::new(ServerConfig {
+            resolvers: vec![resolver.to_string()],
+            ..ServerConfig::default()
+        });
+        for id in [1, 2] {
+            let reply = handle_query(
+                &query_bytes(id, "missing.example.com"),
+                Transport::Udp,
+                &server,
+            )
+            .unwrap();
+            let reply = DnsMessage::from_bytes(&reply).unwrap();
+            assert_eq!(reply.rcode(), RCODE_NXDOMAIN);
+            assert!(reply.answers.is_empty());
+            assert_eq!(reply.authorities.len(), 1);
+            if id == 2 {
+                // served from the cache, with the SOA's TTL capped at its minimum
+                assert!(reply.authorities[0].ttl <= 300);
+            }
+        }
+        assert_eq!(queries.load(Ordering::SeqCst), 1);
+    }
+
+    #[test]
+    fn test_forwarded_rcode_and_flags_are_merged() {
+        let (resolver, _) = start_fake_resolver();
+        let server = Server::new(ServerConfig {
+            resolvers: vec![resolver.to_string()],
+            cache_size: 0,
+            ..ServerConfig::default()
+        });
+        let forward = |qnames: &[&str]| {
+            let query = DnsMessage {
+                questions: qnames
+                    .iter()
+                    .map(|qname| DnsQuestion::new(qname.parse().unwrap(), 1))
+                    .collect(),
+                ..DnsMessage::query(7, DnsQuestion::new(DnsName::root(), 1))
+            };
+            let reply = handle_query(&query.to_bytes(), Transport::Udp, &server);
+            DnsMessage::from_bytes(&reply.unwrap()).unwrap()
+        };
+
+        let reply = forward(&["a.example.com"]);
+        assert_eq!(reply.rcode(), RCODE_NOERROR);
+        assert!(reply.header.recursion_available);
+        assert!(!reply.header.authoritative_answer);
+
+        // one name exists and one doesn't: both sections come back, as NXDOMAIN
+        let reply = forward(&["a.example.com", "missing.example.com"]);
+        assert_eq!(reply.rcode(), RCODE_NXDOMAIN);
+        assert_eq!(reply.answers.len(), 1);
+        assert_eq!(reply.authorities.len(), 1);
+
+        // a refusal for any part is worse than a missing name
+        let reply = forward(&["missing.example.com", "refused.example.com"]);
+        assert_eq!(reply.rcode(), RCODE_REFUSED);
+    }
+
+    #[test]
+    fn test_reply_status_flags_need_every_response() {
+        let response = |authoritative_answer, authed_data| DnsMessage {
+            header: DnsHeader {
+                authoritative_answer,
+                authed_data,
+                recursion_available: true,
+                ..DnsHeader::default()
+            },
+            ..DnsMessage::default()
+        };
+        let mut status = ReplyStatus::default();
+        status.add_response(&response(true, true));
+        status.add_response(&response(true, false));
+        let mut reply = DnsMessage::default();
+        status.apply_to(&mut reply);
+        assert!(reply.header.authoritative_answer);
+        assert!(!reply.header.authed_data);
+        assert!(reply.header.recursion_available);
+
+        // anything from the cache isn't authoritative
+        status.add_cached(RCODE_NOERROR);
+        status.apply_to(&mut reply);
+        assert!(!reply.header.authoritative_answer);
+        assert!(reply.header.recursion_available);
+
+        status.add_failure();
+        status.apply_to(&mut reply);
+        assert_eq!(reply.rcode(), RCODE_SERVFAIL);
+        assert!(!reply.header.recursion_available);
+    }
+
+    #[test]
+    fn test_other_opcodes_are_not_forwarded() {
+        let (resolver, queries) = start_fake_resolver();
+        let server = Server::new(ServerConfig {
+            resolvers: vec![resolver.to_string()],
+            ..ServerConfig::default()
+        });
+        let mut query = DnsMessage::query(5, DnsQuestion::new("a.example.com".parse().unwrap(), 1));
+        query.header.opcode = 2; // STATUS
+        let reply = handle_query(&query.to_bytes(), Transport::Udp, &server);
+        let reply = DnsMessage::from_bytes(&reply.unwrap()).unwrap();
+        assert_eq!(reply.rcode(), RCODE_NOTIMP);
+        assert!(reply.answers.is_empty());
+        assert_eq!(queries.load(Ordering::SeqCst), 0);
+    }
+
+    #[test]
+    fn test_repeated_questions_are_forwarded_once() {
+        let (resolver, queries) = start_fake_resolver();
+        let server = Server::new(ServerConfig {
+            resolvers: vec![resolver.to_string()],
+            cache_size: 0,
+            ..ServerConfig::default()
+        });
+        let question = DnsQuestion::new("a.example.com".parse().unwrap(), 1);
+        let query = DnsMessage {
+            questions: vec![question; 1000],
+            ..DnsMessage::query(6, DnsQuestion::new(DnsName::root(), 1))
+        };
+        let reply = handle_query(&query.to_bytes(), Transport::Tcp, &server);
+        let reply = DnsMessage::from_bytes(&reply.unwrap()).unwrap();
+        assert_eq!(reply.rcode(), RCODE_NOERROR);
+        assert_eq!(reply.answers.len(), 1);
+        assert_eq!(queries.load(Ordering::SeqCst), 1);
+    }
+
+    #[test]
+    fn test_too_many_forwarded_questions_are_refused() {
+        let (resolver, queries) = start_fake_resolver();
+        let server = Server::new(ServerConfig {
+            resolvers: vec![resolver.to_string()],
+            ..ServerConfig::default()
+        });
+        let query = |count: usize| DnsMessage {
+            questions: (0..count)
+                .map(|i| DnsQuestion::new(format!("q{}.example.com", i).parse().unwrap(), 1))
+                .collect(),
+            ..DnsMessage::query(8, DnsQuestion::new(DnsName::root(), 1))
+        };
+        let reply = handle_query(&query(2000).to_bytes(), Transport::Tcp, &server);
+        let reply = DnsMessage::from_bytes(&reply.unwrap()).unwrap();
+        assert_eq!(reply.rcode(), RCODE_REFUSED);
+        assert!(reply.answers.is_empty());
+        assert_eq!(queries.load(Ordering::SeqCst), 0);
+
+        let reply = handle_query(
+            &query(MAX_FORWARDED_QUESTIONS).to_bytes(),
+            Transport::Tcp,
+            &server,
+        );
+        let reply = DnsMessage::from_bytes(&reply.unwrap()).unwrap();
+        assert_eq!(reply.answers.len(), MAX_FORWARDED_QUESTIONS);
+        assert_eq!(queries.load(Ordering::SeqCst), MAX_FORWARDED_QUESTIONS);
+    }
+
+    #[test]
+    fn test_forwarded_query_keeps_do_bit() {
+        let resolver = UdpSocket::bind("127.0.0.1:0").unwrap();
+        let server = Server::new(ServerConfig {
+            resolvers: vec![resolver.local_addr().unwrap().to_string()],
+            cache_size: 0,
+            ..ServerConfig::default()
+        });
+        for dnssec_ok in [true, false] {
+            let mut query =
+                DnsMessage::query(3, DnsQuestion::new("a.example.com".parse().unwrap(), 1));
+            query.edns = Some(Edns {
+                dnssec_ok,
+                ..Edns::default()
+            });
+            thread::scope(|scope| {
+                scope.spawn(|| handle_query(&query.to_bytes(), Transport::Udp, &server));
+                let mut buf = [0; 512];
+                let (size, source) = resolver.recv_from(&mut buf).unwrap();
+                let forwarded = DnsMessage::from_bytes(&buf[..size]).unwrap();
+                assert_eq!(forwarded.edns.as_ref().unwrap().dnssec_ok, dnssec_ok);
+                let reply = DnsMessage::reply_to(&forwarded);
+                resolver.send_to(&reply.to_bytes(), source).unwrap();
+            });
+        }
+    }
+
+    #[test]
+    fn test_silent_resolver_gets_servfail() {
+        // bound so the queries go somewhere, but never read
+        let resolver = UdpSocket::bind("127.0.0.1:0").unwrap();
+        let server = Server::new(ServerConfig {
+            resolvers: vec![resolver.local_addr().unwrap().to_string()],
+            upstream_timeout: Duration::from_millis(20),
+            upstream_retries: 1,
+            ..ServerConfig::default()
+        });
+        let reply = handle_query(&query_bytes(9, "a.example.com"), Transport::Udp, &server);
+        let reply = DnsMessage::from_bytes(&reply.unwrap()).unwrap();
+        assert_eq!(reply.header.id, 9);
+        assert_eq!(reply.rcode(), RCODE_SERVFAIL);
+        assert!(reply.answers.is_empty());
+    }
+
+    /// A resolver that takes `delay` to answer each query, answering any number
+    /// of them at once.
+    fn start_slow_resolver(delay: Duration) -> String {
+        let resolver = UdpSocket::bind("127.0.0.1:0").unwrap();
+        let address = resolver.local_addr().unwrap().to_string();
+        thread::spawn(move || {
+            let mut buf = [0; 512];
+            while let Ok((size, source)) = resolver.recv_from(&mut buf) {
+                let query = DnsMessage::from_bytes(&buf[..size]).unwrap();
+                let resolver = resolver.try_clone().unwrap();
+                thread::spawn(move || {
+                    thread::sleep(delay);
+                    let mut reply = DnsMessage::reply_to(&query);
+                    reply.answers.push(DnsAnswer::new(
+                        query.questions[0].qname.clone(),
+                        300,
+                        RData::A(Ipv4Addr::new(192, 0, 2, 1)),
+                    ));
+                    resolver.send_to(&reply.to_bytes(), source).unwrap();
+                });
+            }
+        });
+        address
+    }
+
+    #[test]
+    fn test_multi_question_forwards_in_parallel() {
+        let server = Server::new(ServerConfig {
+            resolvers: vec![start_slow_resolver(Duration::from_millis(300))],
+            ..ServerConfig::default()
+        });
+        let query = DnsMessage {
+            questions: ["a.example.com", "b.example.com", "c.example.com"]
+                .iter()
+                .map(|qname| DnsQuestion::new(qname.parse().unwrap(), 1))
+                .collect(),
+            ..DnsMessage::query(1, DnsQuestion::new(DnsName::root(), 1))
+        };
+        let start = Instant::now();
+        let reply = handle_query(&query.to_bytes(), Transport::Udp, &server).unwrap();
+        // one after another would take 900ms
+        assert!(start.elapsed() < Duration::from_millis(700));
+        let reply = DnsMessage::from_bytes(&reply).unwrap();
+        let names: Vec<String> = (reply.answers.iter())
+            .map(|answer| answer.name.to_string())
+            .collect();
+        assert_eq!(
+            names,
+            ["a.example.com.", "b.example.com.", "c.example.com."]
+        );
+    }
+
+    #[test]
+    fn test_multi_question_forwards_share_a_deadline() {
+        // each attempt times out at 200ms and there are two of them, so the
+        // whole reply is SERVFAIL after 400ms however many questions there are
+        let server = Server::new(ServerConfig {
+            resolvers: vec![start_slow_resolver(Duration::from_secs(2))],
+            upstream_timeout: Duration::from_millis(200),
+            upstream_retries: 1,
+            ..ServerConfig::default()
+        });
+        let query = DnsMessage {
+            questions: vec![DnsQuestion::new("a.example.com".parse().unwrap(), 1); 4],
+            ..DnsMessage::query(1, DnsQuestion::new(DnsName::root(), 1))
+        };
+        let start = Instant::now();
+        let reply = handle_query(&query.to_bytes(), Transport::Udp, &server).unwrap();
+        assert!(start.elapsed() < Duration::from_millis(700));
+        let reply = DnsMessage::from_bytes(&reply).unwrap();
+        assert_eq!(reply.rcode(), RCODE_SERVFAIL);
+    }
+
+    #[test]
+    fn test_udp_queries_answered_concurrently() {
+        // every lookup takes 300ms, but they're answered in parallel
+        let resolver = start_slow_resolver(Duration::from_millis(300));
+        let socket = UdpSocket::bind("127.0.0.1:0").unwrap();
+        let addr = socket.local_addr().unwrap();
+        let server = Server::new(ServerConfig {
+            resolvers: vec![resolver],
+            workers: 4,
+            ..ServerConfig::default()
+        });
+        thread::spawn(move || serve_udp(socket, Arc::new(server)));
+
+        let client = UdpSocket::bind("127.0.0.1:0").unwrap();
+        client
+            .set_read_timeout(Some(Duration::from_secs(5)))
+            .unwrap();
+        let start = Instant::now();
+        for id in 0..4 {
+            let qname = format!("{}.example.com", id);
+            client.send_to(&query_bytes(id, &qname), addr).unwrap();
+        }
+        let mut buf = [0; 512];
+        for _ in 0..4 {
+            let (size, _) = client.recv_from(&mut buf).unwrap();
+            assert_eq!(DnsMessage::from_bytes(&buf[..size]).unwrap().rcode(), 0);
+        }
+        // one at a time would take 1.2s
+        assert!(start.elapsed() < Duration::from_millis(900));
+    }
+
+    #[test]
+    fn test_forward_rules_pick_longest_suffix() {
+        let (corp, corp_queries) = start_fake_resolver();
+        let (internal, internal_queries) = start_fake_resolver();
+        let server = Server::new(ServerConfig {
+            forward_rules: vec![
+                ForwardRule {
+                    suffix: "internal".parse().unwrap(),
+                    upstreams: vec![internal.to_string()],
+                },
+                ForwardRule {
+                    suffix: "corp.internal".parse().unwrap(),
+                    upstreams: vec![corp.to_string()],
+                },
+            ],
+            cache_size: 0,
+            ..ServerConfig::default()
+        });
+        let query = DnsMessage {
+            questions: [
+                "db.corp.internal",
+                "corp.internal",
+                "lab.internal",
+                "example.com",
+            ]
+            .iter()
+            .map(|qname| DnsQuestion::new(qname.parse().unwrap(), 1))
+            .collect(),
+            ..DnsMessage::query(1, DnsQuestion::new(DnsName::root(), 1))
+        };
+        let reply = handle_query(&query.to_bytes(), Transport::Udp, &server).unwrap();
+        let reply = DnsMessage::from_bytes(&reply).unwrap();
+        assert_eq!(corp_queries.load(Ordering::SeqCst), 2);
+        assert_eq!(internal_queries.load(Ordering::SeqCst), 1);
+        // with no default resolvers, example.com is answered locally
+        assert_eq!(reply.answers.len(), 4);
+        assert_eq!(reply.answers[3].rdata, RData::A(Ipv4Addr::new(8, 8, 8, 8)));
+        let served: Vec<u64> = (server.upstream_stats().iter())
+            .map(|stats| stats.answered)
+            .collect();
+        assert_eq!(served, [2, 1]);
+    }
+
+    fn parse_zone(origin: &str, text: &str) -> Zone {
+        let origin: DnsName = origin.parse().unwrap();
+        let records = crate::master::parse_master(text, &origin).unwrap();
+        Zone::new(origin, records).unwrap()
+    }
+
+    fn zone_server(resolvers: Vec<String>) -> Server {
+        let zone = parse_zone(
+            "example.com",
+            "
+$TTL 3600
+@    SOA ns1 hostmaster 1 7200 900 1209600 300
+     NS  ns1
+ns1  A   192.0.2.1
+www  A   192.0.2.10
+alias   CNAME www
+alias2  CNAME alias
+legacy  DNAME example.com.
+dangling CNAME nope
+loop1   CNAME loop2
+loop2   CNAME loop1
+c0 CNAME c1
+c1 CNAME c2
+c2 CNAME c3
+c3 CNAME c4
+c4 CNAME c5
+c5 CNAME c6
+c6 CNAME c7
+c7 CNAME c8
+c8 CNAME c9
+c9 CNAME www
+external CNAME www.example.org.
+",
+        );
+        Server::new(ServerConfig {
+            zones: vec![zone],
+            resolvers,
+            cache_size: 0,
+            ..ServerConfig::default()
+        })
+    }
+
+    fn ask(server: &Server, qname: &str, qtype: u16) -> DnsMessage {
+        let query = DnsMessage::query(7, DnsQuestion::new(qname.parse().unwrap(), qtype));
+        let reply = handle_query(&query.to_bytes(), Transport::Udp, server).unwrap();
+        DnsMessage::from_bytes(&reply).unwrap()
+    }
+
+    #[test]
+    fn test_zone_answers_are_authoritative() {
+        let server = zone_server(Vec::new());
+        let reply = ask(&server, "www.example.com", TYPE_A);
+        assert_eq!(reply.rcode(), RCODE_NOERROR);
+        assert!(reply.header.authoritative_answer);
+        assert!(!reply.header.recursion_available);
+        assert_eq!(
+            reply.answers[0].rdata,
+            RData::A(Ipv4Addr::new(192, 0, 2, 10))
+        );
+        assert!(reply.authorities.is_empty());
+
+        let reply = ask(&server, "nope.example.com", TYPE_A);
+        assert_eq!(reply.rcode(), RCODE_NXDOMAIN);
+        assert!(reply.header.authoritative_answer);
+        assert!(reply.answers.is_empty());
+        assert_eq!(reply.authorities.len(), 1);
+        assert!(matches!(reply.authorities[0].rdata, RData::Soa { .. }));
+        assert_eq!(reply.authorities[0].ttl, 300);
+
+        // NODATA: the name is there, the type isn't
+        let reply = ask(&server, "www.example.com", TYPE_AAAA);
+        assert_eq!(reply.rcode(), RCODE_NOERROR);
+        assert!(reply.header.authoritative_answer);
+        assert!(reply.answers.is_empty());
+        assert_eq!(reply.authorities[0].name, "example.com".parse().unwrap());
+
+        // nowhere to send questions outside the zone
+        let reply = ask(&server, "www.example.org", TYPE_A);
+        assert_eq!(reply.rcode(), RCODE_REFUSED);
+        assert!(!reply.header.authoritative_answer);
+        assert!(reply.answers.is_empty());
+    }
+
+    #[test]
+    fn test_zone_questions_are_not_forwarded() {
+        let (resolver, queries) = start_fake_resolver();
+        let server = zone_server(vec![resolver.to_string()]);
+        let reply = ask(&server, "www.example.com", TYPE_A);
+        assert!(reply.header.authoritative_answer);
+        assert!(reply.header.recursion_available);
+        assert_eq!(queries.load(Ordering::SeqCst), 0);
+
+        let reply = ask(&server, "www.example.org", TYPE_A);
+        assert!(!reply.header.authoritative_answer);
+        assert_eq!(reply.answers.len(), 1);
+        assert_eq!(queries.load(Ordering::SeqCst), 1);
+    }
+
+    fn chain(reply: &DnsMessage) -> Vec<String> {
+        (reply.answers.iter())
+            .map(|answer| format!("{} {}", answer.name, answer.rdata))
+            .collect()
+    }
+
+    #[test]
+    fn test_cname_chains_followed_through_zones() {
+        let server = zone_server(Vec::new());
+        let reply = ask(&server, "alias2.example.com", TYPE_A);
+        assert_eq!(reply.rcode(), RCODE_NOERROR);
+        assert!(reply.header.authoritative_answer);
+        assert_eq!(
+            chain(&reply),
+            [
+                "alias2.example.com. alias.example.com.",
+                "alias.example.com. www.example.com.",
+                "www.example.com. 192.0.2.10",
+            ]
+        );
+        // asking for the CNAME itself doesn't follow it
+        let reply = ask(&server, "alias2.example.com", TYPE_CNAME);
+        assert_eq!(chain(&reply), ["alias2.example.com. alias.example.com."]);
+
+        // the rcode and SOA are for the end of the chain
+        let reply = ask(&server, "dangling.example.com", TYPE_A);
+        assert_eq!(reply.rcode(), RCODE_NXDOMAIN);
+        assert_eq!(chain(&reply), ["dangling.example.com. nope.example.com."]);
+        assert!(matches!(reply.authorities[0].rdata, RData::Soa { .. }));
+
+        // with nowhere to forward it, the client finishes the chain itself
+        let reply = ask(&server, "external.example.com", TYPE_A);
+        assert_eq!(reply.rcode(), RCODE_NOERROR);
+        assert_eq!(chain(&reply), ["external.example.com. www.example.org."]);
+    }
+
+    #[test]
+    fn test_dname_followed_through_zones() {
+        let server = zone_server(Vec::new());
+        let reply = ask(&server, "www.legacy.example.com", TYPE_A);
+        assert_eq!(reply.rcode(), RCODE_NOERROR);
+        assert_eq!(
+            chain(&reply),
+            [
+                "legacy.example.com. example.com.",
+                "www.legacy.example.com. www.example.com.",
+                "www.example.com. 192.0.2.10",
+            ]
+        );
+        assert_eq!(reply.answers[0].qtype, TYPE_DNAME);
+    }
+
+    #[test]
+    fn test_cname_loops_and_long_chains_servfail() {
+        let server = zone_server(Vec::new());
+        let reply = ask(&server, "loop1.example.com", TYPE_A);
+        assert_eq!(reply.rcode(), RCODE_SERVFAIL);
+        assert_eq!(reply.answers.len(), 2);
+        // c0 to www is ten CNAMEs, two more than we'll follow
+        let reply = ask(&server, "c0.example.com", TYPE_A);
+        assert_eq!(reply.rcode(), RCODE_SERVFAIL);
+        let reply = ask(&server, "c2.example.com", TYPE_A);
+        assert_eq!(reply.rcode(), RCODE_NOERROR);
+        assert_eq!(reply.answers.len(), 9);
+    }
+
+    #[test]
+    fn test_cname_targets_outside_zones_go_upstream() {
+        let (resolver, queries) = start_fake_resolver();
+        let server = zone_server(vec![resolver.to_string()]);
+        let reply = ask(&server, "external.example.com", TYPE_A);
+        assert_eq!(reply.rcode(), RCODE_NOERROR);
+        assert!(reply.header.authoritative_answer);
+        assert_eq!(
+            chain(&reply),
+            [
+                "external.example.com. www.example.org.",
+                "www.example.org. 192.0.2.1",
+            ]
+        );
+        assert_eq!(queries.load(Ordering::SeqCst), 1);
+    }
+
+    #[test]
+    fn test_forwarded_cnames_are_chased() {
+        let (resolver, queries) = start_fake_resolver();
+        let server = Server::new(ServerConfig {
+            resolvers: vec![resolver.to_string()],
+            ..ServerConfig::default()
+        });
+        let expected = [
+            "www.alias.example.com. target.example.org.",
+            "target.example.org. 192.0.2.1",
+        ];
+        let reply = ask(&server, "www.alias.example.com", TYPE_A);
+        assert_eq!(reply.rcode(), RCODE_NOERROR);
+        assert_eq!(chain(&reply), expected);
+        assert_eq!(queries.load(Ordering::SeqCst), 2);
+        // both halves are cached now
+        let reply = ask(&server, "www.alias.example.com", TYPE_A);
+        assert_eq!(chain(&reply), expected);
+        assert_eq!(queries.load(Ordering::SeqCst), 2);
+    }
+
+    #[test]
+    fn test_chase_stops_when_the_target_has_other_types() {
+        // the fake resolver answers ANY for the target with just an A record,
+        // which neither matches the qtype nor is another CNAME
+        let (resolver, queries) = start_fake_resolver();
+        let server = Server::new(ServerConfig {
+            resolvers: vec![resolver.to_string()],
+            ..ServerConfig::default()
+        });
+        let reply = ask(&server, "www.alias.example.com", 255);
+        assert_eq!(reply.rcode(), RCODE_NOERROR);
+        assert_eq!(
+            chain(&reply),
+            [
+                "www.alias.example.com. target.example.org.",
+                "target.example.org. 192.0.2.1",
+            ]
+        );
+        assert_eq!(queries.load(Ordering::SeqCst), 2);
+    }
+
+    #[test]
+    fn test_nested_delegations() {
+        let parent = parse_zone(
+            "example.com",
+            "
+$TTL 3600
+@         SOA ns1 hostmaster 1 7200 900 1209600 300
+          NS  ns1
+ns1       A   192.0.2.1
+sub       NS  ns1.sub
+ns1.sub   A   192.0.2.53
+",
+        );
+        let child = parse_zone(
+            "sub.example.com",
+            "
+$TTL 3600
+@             SOA ns1 hostmaster 1 7200 900 1209600 300
+              NS  ns1
+ns1           A   192.0.2.53
+www           A   192.0.2.80
+deep          NS  ns.deep
+              NS  ns1.example.com.
+ns.deep       AAAA 2001:db8::5
+",
+        );
+        let referral = |reply: &DnsMessage| {
+            assert_eq!(reply.rcode(), RCODE_NOERROR);
+            assert!(!reply.header.authoritative_answer);
+            assert!(reply.answers.is_empty());
+            let ns: Vec<String> = reply.authorities.iter().map(|r| r.to_string()).collect();
+            let glue: Vec<String> = reply.additionals.iter().map(|r| r.to_string()).collect();
+            (ns, glue)
+        };
+
+        // with only the parent loaded, anything under sub gets sent there
+        let server = Server::new(ServerConfig {
+            zones: vec![parent.clone()],
+            ..ServerConfig::default()
+        });
+        for qname in ["www.sub.example.com", "x.deep.sub.example.com"] {
+            assert_eq!(
+                referral(&ask(&server, qname, TYPE_A)),
+                (
+                    vec!["sub.example.com. 3600 IN NS ns1.sub.example.com.".to_string()],
+                    vec!["ns1.sub.example.com. 3600 IN A 192.0.2.53".to_string()]
+                )
+            );
+        }
+
+        // with the child loaded as well, the child answers for itself and
+        // refers on for the grandchild
+        let server = Server::new(ServerConfig {
+            zones: vec![parent, child],
+            ..ServerConfig::default()
+        });
+        let reply = ask(&server, "www.sub.example.com", TYPE_A);
+        assert!(reply.header.authoritative_answer);
+        assert_eq!(chain(&reply), ["www.sub.example.com. 192.0.2.80"]);
+        assert_eq!(
+            referral(&ask(&server, "x.deep.sub.example.com", TYPE_A)),
+            (
+                vec![
+                    "deep.sub.example.com. 3600 IN NS ns.deep.sub.example.com.".to_string(),
+                    "deep.sub.example.com. 3600 IN NS ns1.example.com.".to_string(),
+                ],
+                vec!["ns.deep.sub.example.com. 3600 IN AAAA 2001:db8::5".to_string()]
+            )
+        );
+        // a referral alongside an answer isn't authoritative as a whole
+        let query = DnsMessage {
+            questions: vec![
+                DnsQuestion::new("www.sub.example.com".parse().unwrap(), TYPE_A),
+                DnsQuestion::new("x.deep.sub.example.com".parse().unwrap(), TYPE_A),
+            ],
+            ..DnsMessage::query(1, DnsQuestion::new(DnsName::root(), 1))
+        };
+        let reply = handle_query(&query.to_bytes(), Transport::Udp, &server).unwrap();
+        let reply = DnsMessage::from_bytes(&reply).unwrap();
+        assert!(!reply.header.authoritative_answer);
+        assert_eq!(reply.answers.len(), 1);
+        assert_eq!(reply.authorities.len(), 2);
+    }
+}
@@ -1,5 +1,28 @@
-#![allow(dead_code)]
+//! The wire format: the 12 byte header, questions, resource records and whole
+//! messages, each with `to_bytes` and a Result-returning `from_bytes`.
 
+use std::collections::HashMap;
+use std::fmt;
+use std::str::FromStr;
+
+use crate::edns::{Edns, CLASSIC_UDP_PAYLOAD_SIZE, TYPE_OPT};
+use crate::error::{DnsParseError, ParseResult, ZoneError};
+use crate::master::parse_record;
+use crate::name::{DnsName, MAX_NAME_LEN};
+use crate::rdata::{type_name, RData, CLASS_IN, TYPE_A, TYPE_AAAA};
+
+pub const RCODE_NOERROR: u16 = 0;
+pub const RCODE_FORMERR: u16 = 1;
+pub const RCODE_SERVFAIL: u16 = 2;
+pub const RCODE_NXDOMAIN: u16 = 3;
+pub const RCODE_NOTIMP: u16 = 4;
+pub const RCODE_REFUSED: u16 = 5;
+/// A DNAME substitution made a name longer than 255 bytes (RFC 6672 2.2).
+pub const RCODE_YXDOMAIN: u16 = 6;
+/// Needs EDNS, since it doesn't fit in the header's four bits (RFC 6891 9).
+pub const RCODE_BADVERS: u16 = 16;
+
+/// The fixed 12 byte header at the start of every message (RFC 1035 4.1.1).
 #[derive(Debug, Clone, PartialEq, Eq)]
 pub struct DnsHeader {
     pub id: u16,
@@ -51,11 +74,11 @@ impl DnsHeader {
             | (self.truncated_message as u8) << 1
             | self.recursion_desired as u8;
         bytes.push(first_flag_bytes);
-        let second_flag_bytes = (self.checking_disabled as u8) << 7
+        let second_flag_bytes = (self.recursion_available as u8) << 7
+            | (self.z as u8) << 6
             | (self.authed_data as u8) << 5
-            | (self.z as u8) << 4
-            | (self.recursion_available as u8) << 3
-            | self.rescode;
+            | (self.checking_disabled as u8) << 4
+            | (self.rescode & 0b1111);
         bytes.push(second_flag_bytes);
         bytes.extend_from_slice(&self.questions.to_be_bytes());
         bytes.extend_from_slice(&self.answers.to_be_bytes());
@@ -65,8 +88,13 @@ impl DnsHeader {
         bytes
     }
 
-    pub fn from_bytes(bytes: &[u8]) -> DnsHeader {
-        assert!(bytes.len() == 12, "DnsHeader is not 12 bytes long");
+    pub fn from_bytes(bytes: &[u8]) -> ParseResult<DnsHeader> {
+        if bytes.len() < 12 {
+            return Err(DnsParseError::Truncated {
+                offset: bytes.len(),
+                needed: 12 - bytes.len(),
+            });
+        }
         let id = u16::from_be_bytes([bytes[0], bytes[1]]);
         let first_flag_bytes = bytes[2];
         let response = first_flag_bytes & 0b1000_0000 != 0;
@@ -75,16 +103,16 @@ impl DnsHeader {
         let truncated_message = first_flag_bytes & 0b0000_0010 != 0;
         let recursion_desired = first_flag_bytes & 0b0000_0001 != 0;
         let second_flag_bytes = bytes[3];
-        let checking_disabled = second_flag_bytes & 0b1000_0000 != 0;
-        let authed_data = second_flag_bytes & 0b0100_0000 != 0;
-        let z = second_flag_bytes & 0b0010_0000 != 0;
-        let recursion_available = second_flag_bytes & 0b0001_0000 != 0;
+        let recursion_available = second_flag_bytes & 0b1000_0000 != 0;
+        let z = second_flag_bytes & 0b0100_0000 != 0;
+        let authed_data = second_flag_bytes & 0b0010_0000 != 0;
+        let checking_disabled = second_flag_bytes & 0b0001_0000 != 0;
         let rescode = second_flag_bytes & 0b0000_1111;
         let questions = u16::from_be_bytes([bytes[4], bytes[5]]);
         let answers = u16::from_be_bytes([bytes[6], bytes[7]]);
         let authoritative_entries = u16::from_be_bytes([bytes[8], bytes[9]]);
         let resource_entries = u16::from_be_bytes([bytes[10], bytes[11]]);
-        DnsHeader {
+        Ok(DnsHeader {
             id,
             response,
             recursion_desired,
@@ -100,188 +128,544 @@ impl DnsHeader {
             answers,
             authoritative_entries,
             resource_entries,
-        }
+        })
     }
 }
 
-#[derive(Debug, Clone, PartialEq, Eq)]
+/// One entry of the question section.
+#[derive(Debug, Clone, PartialEq, Eq, Hash)]
 pub struct DnsQuestion {
-    pub qname: String, // the domain name that is being queried
-    pub qtype: u16,    // 2 bytes, the type of record being queried (A, MX, CNAME, etc.)
-    pub qclass: u16,   // the class of the query (usually IN for internet)
+    pub qname: DnsName, // the domain name that is being queried
+    pub qtype: u16,     // 2 bytes, the type of record being queried (A, MX, CNAME, etc.)
+    pub qclass: u16,    // the class of the query (usually IN for internet)
 }
 
 impl DnsQuestion {
+    /// A question for `qname` in class IN.
+    pub fn new(qname: DnsName, qtype: u16) -> DnsQuestion {
+        DnsQuestion {
+            qname,
+            qtype,
+            qclass: CLASS_IN,
+        }
+    }
+
     pub fn to_bytes(&self) -> Vec<u8> {
         let mut bytes = Vec::new();
+        self.write_to(&mut bytes, &mut CompressionTable::disabled());
+        bytes
+    }
+
+    /// Appends the question to a message being built in `bytes`.
+    pub fn write_to(&self, bytes: &mut Vec<u8>, names: &mut CompressionTable) {
         // labels are encoded as a byte for the length of the label followed by the label itself
-        let name_bytes = write_name(&self.qname);
-        bytes.extend_from_slice(&name_bytes);
+        names.write_name(&self.qname, bytes);
         bytes.extend_from_slice(&self.qtype.to_be_bytes());
         bytes.extend_from_slice(&self.qclass.to_be_bytes());
-        bytes
     }
 
-    pub fn from_bytes(bytes: &[u8], message_bytes: &[u8]) -> DnsQuestion {
+    pub fn from_bytes(bytes: &[u8], message_bytes: &[u8]) -> ParseResult<DnsQuestion> {
+        Self::decode(bytes, message_bytes).map(|(question, _)| question)
+    }
+
+    /// Like `from_bytes`, but also returns how many bytes of `bytes` the question used.
+    pub fn decode(bytes: &[u8], message_bytes: &[u8]) -> ParseResult<(DnsQuestion, usize)> {
+        let base = offset_in_message(bytes, message_bytes);
         let mut i = 0;
         // if compression is used, then the first two bits of the first byte will be 11
         // and the remaining 14 bits will be an offset to the actual domain name
         // the offset is a 14-bit number that is the number of bytes from the start of the message
-        let (qname, j) = read_name(&bytes[i..], message_bytes);
+        let (qname, j) = read_name(&bytes[i..], message_bytes)?;
         i += j;
-        let qtype = u16::from_be_bytes([bytes[i], bytes[i + 1]]);
-        assert!((1..=16).contains(&qtype), "Invalid qtype");
-        let qclass = u16::from_be_bytes([bytes[i + 2], bytes[i + 3]]);
-        assert!(qclass == 1, "Invalid qclass");
-        DnsQuestion {
+        let qtype = read_u16(bytes, i, base)?;
+        let qclass = read_u16(bytes, i + 2, base)?;
+        if qclass != 1 {
+            return Err(DnsParseError::UnsupportedClass {
+                offset: base.unwrap_or(0) + i + 2,
+                qclass,
+            });
+        }
+        let question = DnsQuestion {
             qname,
             qtype,
             qclass,
-        }
+        };
+        Ok((question, i + 4))
     }
 }
 
+/// A resource record. Despite the name it's used for every section, not just
+/// answers.
 #[derive(Debug, Clone, PartialEq, Eq)]
 pub struct DnsAnswer {
-    pub name: String, // the domain name that was queried
+    pub name: DnsName, // the domain name that was queried
     pub qtype: u16,
     pub qclass: u16,
     pub ttl: u32,
-    pub rdlength: u16,
-    pub rdata: Vec<u8>,
+    pub rdata: RData, // rdlength is worked out from this when encoding
 }
 
 impl DnsAnswer {
+    /// A class IN record, with its type taken from `rdata`.
+    pub fn new(name: DnsName, ttl: u32, rdata: RData) -> DnsAnswer {
+        DnsAnswer {
+            name,
+            qtype: rdata.rtype(),
+            qclass: CLASS_IN,
+            ttl,
+            rdata,
+        }
+    }
+
     pub fn to_bytes(&self) -> Vec<u8> {
         let mut bytes = Vec::new();
-        let name_bytes = write_name(&self.name);
-        bytes.extend_from_slice(&name_bytes);
+        self.write_to(&mut bytes, &mut CompressionTable::disabled());
+        bytes
+    }
+
+    /// Appends the record to a message being built in `bytes`.
+    pub fn write_to(&self, bytes: &mut Vec<u8>, names: &mut CompressionTable) {
+        names.write_name(&self.name, bytes);
         bytes.extend_from_slice(&self.qtype.to_be_bytes());
         bytes.extend_from_slice(&self.qclass.to_be_bytes());
         bytes.extend_from_slice(&self.ttl.to_be_bytes());
-        bytes.extend_from_slice(&self.rdlength.to_be_bytes());
-        bytes.extend_from_slice(&self.rdata);
-        bytes
+        // rdlength isn't known until the (possibly compressed) rdata is written
+        let rdlength_at = bytes.len();
+        bytes.extend_from_slice(&[0, 0]);
+        self.rdata.write_to(bytes, names);
+        let rdlength = (bytes.len() - rdlength_at - 2) as u16;
+        bytes[rdlength_at..rdlength_at + 2].copy_from_slice(&rdlength.to_be_bytes());
+    }
+
+    pub fn from_bytes(bytes: &[u8], message_bytes: &[u8]) -> ParseResult<DnsAnswer> {
+        Self::decode(bytes, message_bytes).map(|(answer, _)| answer)
     }
 
-    pub fn from_bytes(bytes: &[u8], message_bytes: &[u8]) -> DnsAnswer {
+    /// Like `from_bytes`, but also returns how many bytes of `bytes` the record used.
+    pub fn decode(bytes: &[u8], message_bytes: &[u8]) -> ParseResult<(DnsAnswer, usize)> {
+        let base = offset_in_message(bytes, message_bytes);
         let mut i = 0;
-        let (name, j) = read_name(&bytes[i..], message_bytes);
+        let (name, j) = read_name(&bytes[i..], message_bytes)?;
         i += j;
-        let qtype = u16::from_be_bytes([bytes[i], bytes[i + 1]]);
-        assert!((1..=16).contains(&qtype), "Invalid qtype");
-        let qclass = u16::from_be_bytes([bytes[i + 2], bytes[i + 3]]);
-        assert!(qclass == 1, "Invalid qclass");
-        let ttl = u32::from_be_bytes([bytes[i + 4], bytes[i + 5], bytes[i + 6], bytes[i + 7]]);
-        let rdlength = u16::from_be_bytes([bytes[i + 8], bytes[i + 9]]);
-        let rdata = bytes[i + 10..i + 10 + rdlength as usize].to_vec();
-        DnsAnswer {
+        // any type and class is fine here, unknown types just keep their rdata as
+        // raw bytes; OPT records in particular use the class field for the UDP
+        // payload size
+        let qtype = read_u16(bytes, i, base)?;
+        let qclass = read_u16(bytes, i + 2, base)?;
+        let ttl = read_u32(bytes, i + 4, base)?;
+        let rdlength = read_u16(bytes, i + 8, base)?;
+        take(bytes, i + 10, rdlength as usize, base)?;
+        let rdata = RData::decode(qtype, &bytes[i + 10..], rdlength as usize, message_bytes)?;
+        let answer = DnsAnswer {
             name,
             qtype,
             qclass,
             ttl,
-            rdlength,
             rdata,
+        };
+        Ok((answer, i + 10 + rdlength as usize))
+    }
+}
+
+impl fmt::Display for DnsAnswer {
+    /// The record as a master file line: `www.example.com. 300 IN A 192.0.2.1`.
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{} {} ", self.name, self.ttl)?;
+        match self.qclass {
+            CLASS_IN => f.write_str("IN")?,
+            qclass => write!(f, "CLASS{}", qclass)?,
         }
+        write!(f, " {} {}", type_name(self.qtype), self.rdata)
     }
 }
 
-#[derive(Debug, Clone, PartialEq, Eq)]
+impl FromStr for DnsAnswer {
+    type Err = ZoneError;
+
+    /// Reads a record from one master file line, with the root as the origin so
+    /// every name is absolute; `master::parse_record` takes another origin.
+    fn from_str(s: &str) -> Result<DnsAnswer, ZoneError> {
+        parse_record(s, &DnsName::root())
+    }
+}
+
+/// A whole message. The section counts in `header` are only informational:
+/// `to_bytes` always writes the real lengths of the vectors.
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
 pub struct DnsMessage {
     pub header: DnsHeader,
     pub questions: Vec<DnsQuestion>,
     pub answers: Vec<DnsAnswer>,
+    pub authorities: Vec<DnsAnswer>,
+    pub additionals: Vec<DnsAnswer>, // never includes the OPT record, see `edns`
+    pub edns: Option<Edns>,
 }
 
 impl DnsMessage {
+    /// A standard query asking for recursion.
+    pub fn query(id: u16, question: DnsQuestion) -> DnsMessage {
+        DnsMessage {
+            header: DnsHeader {
+                id,
+                response: false,
+                recursion_desired: true,
+                ..DnsHeader::default()
+            },
+            questions: vec![question],
+            ..DnsMessage::default()
+        }
+    }
+
+    /// An empty NOERROR response to `query`, echoing its id, opcode, RD bit and
+    /// questions. It has EDNS only if the query did (RFC 6891 7).
+    pub fn reply_to(query: &DnsMessage) -> DnsMessage {
+        DnsMessage {
+            header: DnsHeader {
+                id: query.header.id,
+                response: true,
+                opcode: query.header.opcode,
+                recursion_desired: query.header.recursion_desired,
+                ..DnsHeader::default()
+            },
+            questions: query.questions.clone(),
+            edns: query.edns.as_ref().map(|edns| Edns {
+                dnssec_ok: edns.dnssec_ok,
+                ..Edns::default()
+            }),
+            ..DnsMessage::default()
+        }
+    }
+
     pub fn to_bytes(&self) -> Vec<u8> {
         let mut bytes = Vec::new();
-        bytes.extend_from_slice(&self.header.to_bytes());
+        // the section counts always come from what we actually write, whatever the
+        // header we were given says
+        let header = DnsHeader {
+            questions: self.questions.len() as u16,
+            answers: self.answers.len() as u16,
+            authoritative_entries: self.authorities.len() as u16,
+            resource_entries: (self.additionals.len() + self.edns.is_some() as usize) as u16,
+            ..self.header.clone()
+        };
+        bytes.extend_from_slice(&header.to_bytes());
+        let mut names = CompressionTable::new();
         for question in &self.questions {
-            bytes.extend_from_slice(&question.to_bytes());
+            question.write_to(&mut bytes, &mut names);
+        }
+        for record in self
+            .answers
+            .iter()
+            .chain(&self.authorities)
+            .chain(&self.additionals)
+        {
+            record.write_to(&mut bytes, &mut names);
         }
-        for answer in &self.answers {
-            bytes.extend_from_slice(&answer.to_bytes());
+        if let Some(edns) = &self.edns {
+            edns.to_record().write_to(&mut bytes, &mut names);
         }
         bytes
     }
 
-    pub fn from_bytes(bytes: &[u8]) -> DnsMessage {
-        let header = DnsHeader::from_bytes(&bytes[..12]);
-        let num_questions = header.questions as usize;
-        let mut questions = Vec::new();
+    /// Decodes a whole message, pulling any OPT record out into `edns`.
+    pub fn from_bytes(bytes: &[u8]) -> ParseResult<DnsMessage> {
+        let header = DnsHeader::from_bytes(bytes)?;
         let mut i = 12;
-        for _ in 0..num_questions {
-            let question = DnsQuestion::from_bytes(&bytes[i..], bytes);
-            questions.push(question.clone());
-            i += question.to_bytes().len();
+        // a question is at least a root name plus type and class, a record at least
+        // that plus ttl and rdlength, so reject counts that can't possibly fit
+        // before trying to decode anything
+        check_count("question", header.questions, 5, bytes.len() - i)?;
+        let mut questions = Vec::new();
+        for _ in 0..header.questions {
+            let (question, len) = DnsQuestion::decode(&bytes[i..], bytes)?;
+            questions.push(question);
+            i += len;
         }
-        let mut answers = Vec::new();
-        for _ in 0..header.answers {
-            let answer = DnsAnswer::from_bytes(&bytes[i..], bytes);
-            i += answer.to_bytes().len();
-            answers.push(answer);
+        let answers = read_records(bytes, &mut i, "answer", header.answers)?;
+        let authorities = read_records(bytes, &mut i, "authority", header.authoritative_entries)?;
+        check_count("additional", header.resource_entries, 11, bytes.len() - i)?;
+        let mut additionals = Vec::new();
+        let mut edns = None;
+        for _ in 0..header.resource_entries {
+            let (record, len) = DnsAnswer::decode(&bytes[i..], bytes)?;
+            if record.qtype == TYPE_OPT {
+                // RFC 6891 6.1.1: more than one OPT record is a format error
+                if edns.is_some() {
+                    return Err(DnsParseError::MultipleOpt { offset: i });
+                }
+                edns = Some(Edns::from_record(&record, i)?);
+            } else {
+                additionals.push(record);
+            }
+            i += len;
         }
-        DnsMessage {
+        Ok(DnsMessage {
             header,
             questions,
             answers,
+            authorities,
+            additionals,
+            edns,
+        })
+    }
+
+    /// The full 12 bit response code, including the upper bits carried in EDNS.
+    pub fn rcode(&self) -> u16 {
+        let extended = self.edns.as_ref().map_or(0, |edns| edns.extended_rcode);
+        (extended as u16) << 4 | self.header.rescode as u16
+    }
+
+    /// Sets the response code, splitting it between the header and EDNS. Codes
+    /// above 15 need the message to have EDNS.
+    pub fn set_rcode(&mut self, rcode: u16) {
+        self.header.rescode = (rcode & 0b1111) as u8;
+        if let Some(edns) = &mut self.edns {
+            edns.extended_rcode = (rcode >> 4) as u8;
         }
     }
+
+    /// Encodes the message, dropping whole RRsets from the end until it fits in
+    /// `max_size` bytes. Losing anything from the answer or authority sections
+    /// sets TC so the client retries over TCP; losing additional records doesn't
+    /// since they're only hints (RFC 2181 9), unless they're glue a referral
+    /// can't be followed without (RFC 9471). The OPT record is always kept.
+    pub fn truncate_to(&mut self, max_size: usize) -> Vec<u8> {
+        let mut bytes = self.to_bytes();
+        while bytes.len() > max_size {
+            let section = if let Some(last) = self.additionals.last() {
+                if self.is_required_glue(last) {
+                    self.header.truncated_message = true;
+                }
+                &mut self.additionals
+            } else if !self.authorities.is_empty() {
+                self.header.truncated_message = true;
+                &mut self.authorities
+            } else if !self.answers.is_empty() {
+                self.header.truncated_message = true;
+                &mut self.answers
+            } else {
+                // nothing left to drop, the question alone is too big
+                break;
+            };
+            let last = section.pop().expect("section is not empty");
+            section.retain(|record| {
+                !(record.name == last.name
+                    && record.qtype == last.qtype
+                    && record.qclass == last.qclass)
+            });
+            bytes = self.to_bytes();
+        }
+        bytes
+    }
+
+    /// Whether `record` is an address for a name server in the authority
+    /// section that lives inside the zone it serves, so it can't be looked up
+    /// without going through the delegation first.
+    fn is_required_glue(&self, record: &DnsAnswer) -> bool {
+        (record.qtype == TYPE_A || record.qtype == TYPE_AAAA)
+            && (self.authorities.iter()).any(|ns| match &ns.rdata {
+                RData::Ns(server) => *server == record.name && server.is_subdomain_of(&ns.name),
+                _ => false,
+            })
+    }
+
+    /// The largest UDP response the sender of this query can take.
+    pub fn max_udp_payload_size(&self) -> usize {
+        self.edns.as_ref().map_or(
+            CLASSIC_UDP_PAYLOAD_SIZE as usize,
+            Edns::effective_payload_size,
+        )
+    }
+}
+
+/// Decodes one resource record section starting at `*i`, leaving `*i` just past it.
+fn read_records(
+    bytes: &[u8],
+    i: &mut usize,
+    section: &'static str,
+    count: u16,
+) -> ParseResult<Vec<DnsAnswer>> {
+    check_count(section, count, 11, bytes.len() - *i)?;
+    let mut records = Vec::new();
+    for _ in 0..count {
+        let (record, len) = DnsAnswer::decode(&bytes[*i..], bytes)?;
+        records.push(record);
+        *i += len;
+    }
+    Ok(records)
 }
 
-fn write_name(name: &str) -> Vec<u8> {
-    let mut bytes = Vec::new();
-    for label in name.split('.') {
-        bytes.push(label.len() as u8);
-        bytes.extend_from_slice(label.as_bytes());
+fn check_count(
+    section: &'static str,
+    count: u16,
+    min_len: usize,
+    remaining: usize,
+) -> ParseResult<()> {
+    if count as usize * min_len > remaining {
+        return Err(DnsParseError::BadCount {
+            section,
+            count,
+            remaining,
+        });
     }
-    bytes.push(0);
+    Ok(())
+}
+
+/// Where `bytes` starts inside `message_bytes`, if it is a suffix of it. Decoders
+/// use this to report error offsets relative to the whole message.
+pub(crate) fn offset_in_message(bytes: &[u8], message_bytes: &[u8]) -> Option<usize> {
+    let base = message_bytes.len().checked_sub(bytes.len())?;
+    std::ptr::eq(message_bytes[base..].as_ptr(), bytes.as_ptr()).then_some(base)
+}
+
+pub(crate) fn take(bytes: &[u8], i: usize, len: usize, base: Option<usize>) -> ParseResult<&[u8]> {
     bytes
+        .get(i..i + len)
+        .ok_or_else(|| DnsParseError::Truncated {
+            offset: base.unwrap_or(0) + i,
+            needed: i + len - bytes.len(),
+        })
 }
 
-fn read_name(bytes: &[u8], message_bytes: &[u8]) -> (String, usize) {
-    let mut name = String::new();
-    let mut i = 0;
-    let mut last_label_compressed = false;
-    loop {
-        let label_len = bytes[i] as usize;
-        if bytes[i] & 0b1100_0000 == 0b1100_0000 {
-            let offset = u16::from_be_bytes([bytes[i], bytes[i + 1]]) & 0b0011_1111_1111_1111;
-            let (name_from_offset, _) = read_name(&message_bytes[offset as usize..], message_bytes);
-            if i != 0 {
-                name.push('.');
-            }
-            name.push_str(&name_from_offset);
-            i += 2;
-            // this break is technically wrong, we should also handle pointers in the middle of the domain name
-            last_label_compressed = true;
-            //return (name, i + 2);
-            continue;
+pub(crate) fn read_u16(bytes: &[u8], i: usize, base: Option<usize>) -> ParseResult<u16> {
+    let b = take(bytes, i, 2, base)?;
+    Ok(u16::from_be_bytes([b[0], b[1]]))
+}
+
+pub(crate) fn read_u32(bytes: &[u8], i: usize, base: Option<usize>) -> ParseResult<u32> {
+    let b = take(bytes, i, 4, base)?;
+    Ok(u32::from_be_bytes([b[0], b[1], b[2], b[3]]))
+}
+
+/// Remembers where names were written in a message so later names that share a
+/// suffix with them can be replaced by a pointer (RFC 1035 4.1.4).
+#[derive(Debug, Default)]
+pub struct CompressionTable {
+    offsets: HashMap<DnsName, usize>,
+    disabled: bool,
+}
+
+impl CompressionTable {
+    pub fn new() -> CompressionTable {
+        CompressionTable::default()
+    }
+
+    /// A table that never compresses, for encoding a question or record on its
+    /// own where there is no message to point back into.
+    pub fn disabled() -> CompressionTable {
+        CompressionTable {
+            disabled: true,
+            ..CompressionTable::default()
         }
-        if label_len == 0 {
-            break;
+    }
+
+    /// Appends `name` to `bytes`, which must be the whole message written so far
+    /// since pointers are offsets from its start.
+    pub fn write_name(&mut self, name: &DnsName, bytes: &mut Vec<u8>) {
+        if self.disabled {
+            bytes.extend_from_slice(&write_name(name));
+            return;
         }
-        if i != 0 {
-            name.push('.');
+        for (skip, label) in name.labels().enumerate() {
+            // DnsName compares case-insensitively, so "WWW.Example.com" can reuse
+            // "www.example.com"
+            let suffix = name.suffix(name.label_count() - skip);
+            if let Some(&offset) = self.offsets.get(&suffix) {
+                bytes.extend_from_slice(&(0xC000 | offset as u16).to_be_bytes());
+                return;
+            }
+            // a pointer only has 14 bits for the offset
+            if bytes.len() < 0x4000 {
+                self.offsets.insert(suffix, bytes.len());
+            }
+            bytes.push(label.len() as u8);
+            bytes.extend_from_slice(label);
         }
-        name.push_str(
-            std::str::from_utf8(&bytes[i + 1..i + 1 + label_len])
-                .expect("Invalid UTF-8 in domain name"),
-        );
-        i += label_len + 1;
-        last_label_compressed = false;
+        bytes.push(0);
     }
-    if !last_label_compressed {
-        assert!(bytes[i] == 0, "Domain name is not null-terminated");
-        i += 1;
+}
+
+pub(crate) fn write_name(name: &DnsName) -> Vec<u8> {
+    name.to_wire()
+}
+
+/// How many compression pointers we'll follow for one name. Pointers have to go
+/// backwards so they can't loop, but a long enough chain is still abuse.
+const MAX_POINTER_JUMPS: usize = 16;
+
+/// Reads a possibly compressed name from the start of `bytes`, returning it and
+/// how many bytes of `bytes` it took up. Pointers are followed into
+/// `message_bytes`.
+pub(crate) fn read_name(bytes: &[u8], message_bytes: &[u8]) -> ParseResult<(DnsName, usize)> {
+    let mut labels = Vec::new();
+    // where we're currently reading labels from; this moves into message_bytes
+    // every time we follow a pointer
+    let mut data = bytes;
+    let mut data_base = offset_in_message(bytes, message_bytes);
+    let mut i = 0;
+    // once we've jumped, the name's length in `bytes` is fixed at the first pointer
+    let mut consumed = None;
+    let mut jumps = 0;
+    let mut wire_len = 0;
+    loop {
+        let at = data_base.unwrap_or(0) + i;
+        let len_byte = take(data, i, 1, data_base)?[0];
+        match len_byte & 0b1100_0000 {
+            0b1100_0000 => {
+                let target = (read_u16(data, i, data_base)? & 0b0011_1111_1111_1111) as usize;
+                // a pointer has to go back to something already in the message,
+                // otherwise it can point at itself (or a later pointer back to it)
+                if data_base.is_some_and(|data_base| target >= data_base + i) {
+                    return Err(DnsParseError::ForwardPointer { offset: at, target });
+                }
+                jumps += 1;
+                if jumps > MAX_POINTER_JUMPS {
+                    return Err(DnsParseError::PointerLoop { offset: at });
+                }
+                if target >= message_bytes.len() {
+                    return Err(DnsParseError::Truncated {
+                        offset: target,
+                        needed: target + 1 - message_bytes.len(),
+                    });
+                }
+                consumed.get_or_insert(i + 2);
+                data = &message_bytes[target..];
+                data_base = Some(target);
+                i = 0;
+            }
+            // the two length bits being clear also caps labels at 63 bytes, so the
+            // labels are always valid for a DnsName
+            0 => {
+                let label_len = len_byte as usize;
+                wire_len += label_len + 1;
+                if wire_len > MAX_NAME_LEN {
+                    return Err(DnsParseError::NameTooLong { offset: at });
+                }
+                if label_len == 0 {
+                    break;
+                }
+                labels.push(take(data, i + 1, label_len, data_base)?.to_vec());
+                i += label_len + 1;
+            }
+            _ => {
+                return Err(DnsParseError::BadLabel {
+                    offset: at,
+                    len: len_byte,
+                })
+            }
+        }
     }
-    (name, i) // skip the null byte
+    let name = DnsName::from_labels(labels).expect("label and name lengths already checked");
+    Ok((name, consumed.unwrap_or(i + 1))) // skip the null byte
 }
 
 #[cfg(test)]
 mod test {
     use super::*;
+    use std::net::Ipv4Addr;
+
+    fn name(s: &str) -> DnsName {
+        s.parse().unwrap()
+    }
 
     #[test]
     fn test_dns_header_to_bytes() {
@@ -338,7 +722,7 @@ mod test {
             0x00,
             0x00,
         ];
-        let header = DnsHeader::from_bytes(&bytes);
+        let header = DnsHeader::from_bytes(&bytes).unwrap();
         assert_eq!(
             header,
             DnsHeader {
@@ -361,10 +745,40 @@ mod test {
         );
     }
 
+    #[test]
+    fn test_dns_header_flag_bits() {
+        // RFC 1035 4.1.1 with AD and CD from RFC 4035 3.2: QR opcode AA TC RD,
+        // then RA Z AD CD rcode
+        let flag_bytes = |header: DnsHeader| {
+            let bytes = header.to_bytes();
+            assert_eq!(DnsHeader::from_bytes(&bytes).unwrap(), header);
+            [bytes[2], bytes[3]]
+        };
+        let base = DnsHeader {
+            response: false,
+            ..DnsHeader::default()
+        };
+        let header = |f: fn(&mut DnsHeader)| {
+            let mut header = base.clone();
+            f(&mut header);
+            flag_bytes(header)
+        };
+        assert_eq!(header(|h| h.response = true), [0x80, 0x00]);
+        assert_eq!(header(|h| h.opcode = 2), [0x10, 0x00]);
+        assert_eq!(header(|h| h.authoritative_answer = true), [0x04, 0x00]);
+        assert_eq!(header(|h| h.truncated_message = true), [0x02, 0x00]);
+        assert_eq!(header(|h| h.recursion_desired = true), [0x01, 0x00]);
+        assert_eq!(header(|h| h.recursion_available = true), [0x00, 0x80]);
+        assert_eq!(header(|h| h.z = true), [0x00, 0x40]);
+        assert_eq!(header(|h| h.authed_data = true), [0x00, 0x20]);
+        assert_eq!(header(|h| h.checking_disabled = true), [0x00, 0x10]);
+        assert_eq!(header(|h| h.rescode = 3), [0x00, 0x03]);
+    }
+
     #[test]
     fn test_dns_question_to_bytes() {
         let question = DnsQuestion {
-            qname: "www.example.com".to_string(),
+            qname: name("www.example.com"),
             qtype: 1,
             qclass: 1,
         };
@@ -384,11 +798,11 @@ mod test {
             0x03, 0x77, 0x77, 0x77, 0x07, 0x65, 0x78, 0x61, 0x6D, 0x70, 0x6C, 0x65, 0x03, 0x63,
             0x6F, 0x6D, 0x00, 0x00, 0x01, 0x00, 0x01,
         ];
-        let question = DnsQuestion::from_bytes(&bytes, &[]);
+        let question = DnsQuestion::from_bytes(&bytes, &[]).unwrap();
         assert_eq!(
             question,
             DnsQuestion {
-                qname: "www.example.com".to_string(),
+                qname: name("www.example.com"),
                 qtype: 1,
                 qclass: 1,
             }
@@ -398,12 +812,11 @@ mod test {
     #[test]
     fn test_dns_answer_to_bytes() {
         let answer = DnsAnswer {
-            name: "www.example.com".to_string(),
+            name: name("www.example.com"),
             qtype: 1,
             qclass: 1,
             ttl: 60,
-            rdlength: 4,
-            rdata: vec![192, 168, 1, 1],
+            rdata: RData::A(Ipv4Addr::new(192, 168, 1, 1)),
         };
         let bytes = answer.to_bytes();
         assert_eq!(
@@ -423,16 +836,15 @@ mod test {
             0x6F, 0x6D, 0x00, 0x00, 0x01, 0x00, 0x01, 0x00, 0x00, 0x00, 0x3C, 0x00, 0x04, 0xC0,
             0xA8, 0x01, 0x01,
         ];
-        let answer = DnsAnswer::from_bytes(&bytes, &[]);
+        let answer = DnsAnswer::from_bytes(&bytes, &[]).unwrap();
         assert_eq!(
             answer,
             DnsAnswer {
-                name: "www.example.com".to_string(),
+                name: name("www.example.com"),
                 qtype: 1,
                 qclass: 1,
                 ttl: 60,
-                rdlength: 4,
-                rdata: vec![192, 168, 1, 1],
+                rdata: RData::A(Ipv4Addr::new(192, 168, 1, 1)),
             }
         );
     }
@@ -448,11 +860,11 @@ mod test {
             0x03, 0x77, 0x77, 0x77, 0x07, 0x65, 0x78, 0x61, 0x6D, 0x70, 0x6C, 0x65, 0x03, 0x63,
             0x6F, 0x6D, 0x00, 0x00, 0x01, 0x00, 0x01,
         ];
-        let question = DnsQuestion::from_bytes(&bytes, message_bytes.as_slice());
+        let question = DnsQuestion::from_bytes(&bytes, message_bytes.as_slice()).unwrap();
         assert_eq!(
             question,
             DnsQuestion {
-                qname: "www.example.com".to_string(),
+                qname: name("www.example.com"),
                 qtype: 1,
                 qclass: 1,
             }
@@ -461,7 +873,8 @@ mod test {
 
     #[test]
     fn test_read_dns_question_compression_in_middle() {
-        // 3 www <pointer to index 0> 3 com 0
+        // 3 www <pointer to index 0>, where the pointer continues the name with
+        // the labels of a name written earlier
         let bytes = vec![
             0x03,
             0x77,
@@ -469,31 +882,60 @@ mod test {
             0x77,
             0b1100_0000,
             0x00,
-            0x03,
-            0x63,
-            0x6F,
-            0x6D,
-            0x00,
             0x00,
             0x01,
             0x00,
             0x01,
         ];
-        // this is a dns record section for "example" type A and class IN
+        // this is a dns record section for "example.com" type A and class IN
         let message_bytes = vec![
-            0x07, 0x65, 0x78, 0x61, 0x6D, 0x70, 0x6C, 0x65, 0x00, 0x00, 0x01, 0x00, 0x01,
+            0x07, 0x65, 0x78, 0x61, 0x6D, 0x70, 0x6C, 0x65, 0x03, 0x63, 0x6F, 0x6D, 0x00, 0x00,
+            0x01, 0x00, 0x01,
         ];
-        let question = DnsQuestion::from_bytes(&bytes, message_bytes.as_slice());
+        let question = DnsQuestion::from_bytes(&bytes, message_bytes.as_slice()).unwrap();
         assert_eq!(
             question,
             DnsQuestion {
-                qname: "www.example.com".to_string(),
+                qname: name("www.example.com"),
                 qtype: 1,
                 qclass: 1,
             }
         );
     }
 
+    #[test]
+    fn test_read_name_pointer_ends_name() {
+        // 3 www <pointer to index 0> 3 com 0: labels after a pointer aren't part
+        // of the name (RFC 1035 4.1.4), so this is www.example followed by
+        // whatever the next field makes of 3 com 0
+        let bytes = vec![
+            0x03,
+            0x77,
+            0x77,
+            0x77,
+            0b1100_0000,
+            0x00,
+            0x03,
+            0x63,
+            0x6F,
+            0x6D,
+            0x00,
+            0x00,
+            0x01,
+            0x00,
+            0x01,
+        ];
+        // this is a dns record section for "example" type A and class IN
+        let message_bytes = vec![
+            0x07, 0x65, 0x78, 0x61, 0x6D, 0x70, 0x6C, 0x65, 0x00, 0x00, 0x01, 0x00, 0x01,
+        ];
+        let (qname, len) = read_name(&bytes, &message_bytes).unwrap();
+        assert_eq!(qname, name("www.example"));
+        assert_eq!(len, 6);
+        // the type and class then come out of "3 com", which isn't class IN
+        assert!(DnsQuestion::from_bytes(&bytes, &message_bytes).is_err());
+    }
+
     #[test]
     fn test_read_dns_question_compression_at_end() {
         // 3 www <pointer to index 0>
@@ -513,14 +955,695 @@ mod test {
         let message_bytes = vec![
             0x07, 0x65, 0x78, 0x61, 0x6D, 0x70, 0x6C, 0x65, 0x00, 0x00, 0x01, 0x00, 0x01,
         ];
-        let question = DnsQuestion::from_bytes(&bytes, message_bytes.as_slice());
+        let question = DnsQuestion::from_bytes(&bytes, message_bytes.as_slice()).unwrap();
         assert_eq!(
             question,
             DnsQuestion {
-                qname: "www.example".to_string(),
+                qname: name("www.example"),
                 qtype: 1,
                 qclass: 1,
             }
         );
     }
+
+    #[test]
+    fn test_dns_header_from_bytes_truncated() {
+        let err = DnsHeader::from_bytes(&[0x04, 0xD2, 0x01]).unwrap_err();
+        assert_eq!(
+            err,
+            DnsParseError::Truncated {
+                offset: 3,
+                needed: 9
+            }
+        );
+    }
+
+    #[test]
+    fn test_dns_header_second_flag_byte() {
+        // a recursive reply with AD set as resolvers send it: QR RD in the
+        // first flag byte, then RA (bit 7) and AD (bit 5) in the second
+        let bytes = [0xAB, 0xCD, 0x81, 0xA0, 0, 1, 0, 0, 0, 0, 0, 0];
+        let header = DnsHeader::from_bytes(&bytes).unwrap();
+        assert!(header.response && header.recursion_desired);
+        assert!(header.recursion_available && header.authed_data);
+        assert!(!header.z && !header.checking_disabled);
+        assert_eq!(header.rescode, 0);
+        assert_eq!(header.to_bytes(), bytes);
+
+        // a query with CD (bit 4) and a SERVFAIL rcode in the low nibble
+        let bytes = [0xAB, 0xCD, 0x01, 0x12, 0, 1, 0, 0, 0, 0, 0, 0];
+        let header = DnsHeader::from_bytes(&bytes).unwrap();
+        assert!(header.checking_disabled);
+        assert!(!header.recursion_available && !header.authed_data && !header.z);
+        assert_eq!(header.rescode, 2);
+        assert_eq!(header.to_bytes(), bytes);
+    }
+
+    #[test]
+    fn test_dns_message_from_bytes_truncated_question() {
+        // header says one question whose first label is 7 bytes long, but the
+        // message ends after 4 of them
+        let bytes = vec![
+            0x04, 0xD2, 0x01, 0x00, 0x00, 0x01, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x07, 0x65,
+            0x78, 0x61, 0x6D,
+        ];
+        let err = DnsMessage::from_bytes(&bytes).unwrap_err();
+        assert_eq!(
+            err,
+            DnsParseError::Truncated {
+                offset: 13,
+                needed: 3
+            }
+        );
+    }
+
+    #[test]
+    fn test_dns_message_from_bytes_bad_count() {
+        // claims 0xFFFF questions with no room for any of them
+        let bytes = vec![
+            0x04, 0xD2, 0x01, 0x00, 0xFF, 0xFF, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00,
+        ];
+        let err = DnsMessage::from_bytes(&bytes).unwrap_err();
+        assert_eq!(
+            err,
+            DnsParseError::BadCount {
+                section: "question",
+                count: 0xFFFF,
+                remaining: 0
+            }
+        );
+    }
+
+    #[test]
+    fn test_read_name_self_pointer() {
+        // header, then a name that is just a pointer to itself
+        let bytes = vec![
+            0x04, 0xD2, 0x01, 0x00, 0x00, 0x01, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0xC0, 0x0C,
+            0x00, 0x01, 0x00, 0x01,
+        ];
+        let err = DnsMessage::from_bytes(&bytes).unwrap_err();
+        assert_eq!(
+            err,
+            DnsParseError::ForwardPointer {
+                offset: 12,
+                target: 12
+            }
+        );
+    }
+
+    #[test]
+    fn test_read_name_bad_label() {
+        // 0b01xx_xxxx is a reserved label type
+        let bytes = vec![0x41, 0x77, 0x00, 0x00, 0x01, 0x00, 0x01];
+        let err = DnsQuestion::from_bytes(&bytes, &[]).unwrap_err();
+        assert_eq!(
+            err,
+            DnsParseError::BadLabel {
+                offset: 0,
+                len: 0x41
+            }
+        );
+    }
+
+    #[test]
+    fn test_read_name_binary_label() {
+        // labels are raw bytes, they don't need to be UTF-8 or even printable
+        let bytes = vec![0x03, 0xFF, 0x2E, 0x00, 0x00, 0x00, 0x01, 0x00, 0x01];
+        let question = DnsQuestion::from_bytes(&bytes, &[]).unwrap();
+        assert_eq!(
+            question.qname.labels().next(),
+            Some(&[0xFF, 0x2E, 0x00][..])
+        );
+        assert_eq!(question.qname.to_string(), "\\255\\.\\000.");
+        assert_eq!(question.to_bytes(), bytes);
+    }
+
+    #[test]
+    fn test_dns_message_round_trip_all_sections() {
+        let message = DnsMessage {
+            header: DnsHeader {
+                id: 0xBEEF,
+                rescode: 3,
+                questions: 1,
+                authoritative_entries: 1,
+                resource_entries: 2,
+                ..DnsHeader::default()
+            },
+            questions: vec![DnsQuestion {
+                qname: name("nope.example.com"),
+                qtype: 1,
+                qclass: 1,
+            }],
+            answers: Vec::new(),
+            authorities: vec![DnsAnswer {
+                name: name("example.com"),
+                qtype: 6,
+                qclass: 1,
+                ttl: 3600,
+                rdata: RData::Soa {
+                    mname: name("ns1.example.com"),
+                    rname: name("hostmaster.example.com"),
+                    serial: 1,
+                    refresh: 7200,
+                    retry: 3600,
+                    expire: 1209600,
+                    minimum: 300,
+                },
+            }],
+            additionals: vec![DnsAnswer {
+                name: name("ns1.example.com"),
+                qtype: 1,
+                qclass: 1,
+                ttl: 3600,
+                rdata: RData::A(Ipv4Addr::new(192, 0, 2, 53)),
+            }],
+            // the OPT record comes back out as `edns`, not as an additional
+            edns: Some(Edns::default()),
+        };
+        let bytes = message.to_bytes();
+        assert_eq!(DnsMessage::from_bytes(&bytes).unwrap(), message);
+    }
+
+    #[test]
+    fn test_dns_message_to_bytes_derives_counts() {
+        let record = DnsAnswer {
+            name: name("a.example"),
+            qtype: 1,
+            qclass: 1,
+            ttl: 60,
+            rdata: RData::A(Ipv4Addr::new(10, 0, 0, 1)),
+        };
+        let message = DnsMessage {
+            // deliberately wrong counts in the header
+            header: DnsHeader {
+                questions: 7,
+                answers: 7,
+                ..DnsHeader::default()
+            },
+            questions: Vec::new(),
+            answers: vec![record.clone(), record.clone()],
+            authorities: vec![record.clone()],
+            additionals: vec![record.clone(), record.clone(), record],
+            edns: None,
+        };
+        let bytes = message.to_bytes();
+        assert_eq!(&bytes[4..12], &[0, 0, 0, 2, 0, 1, 0, 3]);
+        let parsed = DnsMessage::from_bytes(&bytes).unwrap();
+        assert_eq!(parsed.answers.len(), 2);
+        assert_eq!(parsed.authorities.len(), 1);
+        assert_eq!(parsed.additionals.len(), 3);
+    }
+
+    #[test]
+    fn test_dns_answer_from_bytes_aaaa() {
+        // types above 16 used to be rejected outright
+        let answer = DnsAnswer {
+            name: name("www.example.com"),
+            qtype: 28,
+            qclass: 1,
+            ttl: 300,
+            rdata: RData::Aaaa("2001:db8::1".parse().unwrap()),
+        };
+        let bytes = answer.to_bytes();
+        assert_eq!(DnsAnswer::from_bytes(&bytes, &bytes).unwrap(), answer);
+    }
+
+    #[test]
+    fn test_dns_message_to_bytes_compresses_names() {
+        // www.example.com A, answered with a CNAME to example.com, its address
+        // and the zone's NS in authority, laid out the way BIND sends it
+        let message = DnsMessage {
+            header: DnsHeader {
+                id: 0x1234,
+                recursion_desired: true,
+                recursion_available: true,
+                questions: 1,
+                answers: 2,
+                authoritative_entries: 1,
+                ..DnsHeader::default()
+            },
+            questions: vec![DnsQuestion {
+                qname: name("www.example.com"),
+                qtype: 1,
+                qclass: 1,
+            }],
+            answers: vec![
+                DnsAnswer {
+                    name: name("www.example.com"),
+                    qtype: 5,
+                    qclass: 1,
+                    ttl: 300,
+                    rdata: RData::Cname(name("example.com")),
+                },
+                DnsAnswer {
+                    name: name("example.com"),
+                    qtype: 1,
+                    qclass: 1,
+                    ttl: 300,
+                    rdata: RData::A(Ipv4Addr::new(93, 184, 216, 34)),
+                },
+            ],
+            authorities: vec![DnsAnswer {
+                name: name("example.com"),
+                qtype: 2,
+                qclass: 1,
+                ttl: 172800,
+                rdata: RData::Ns(name("a.iana-servers.net")),
+            }],
+            additionals: Vec::new(),
+            edns: None,
+        };
+        let bytes = message.to_bytes();
+        #[rustfmt::skip]
+        let expected = vec![
+            // header
+            0x12, 0x34, 0x81, 0x80, 0x00, 0x01, 0x00, 0x02, 0x00, 0x01, 0x00, 0x00,
+            // question, www.example.com at 12, example.com at 16, com at 24
+            0x03, 0x77, 0x77, 0x77, 0x07, 0x65, 0x78, 0x61, 0x6D, 0x70, 0x6C, 0x65,
+            0x03, 0x63, 0x6F, 0x6D, 0x00, 0x00, 0x01, 0x00, 0x01,
+            // www.example.com CNAME example.com
+            0xC0, 0x0C, 0x00, 0x05, 0x00, 0x01, 0x00, 0x00, 0x01, 0x2C, 0x00, 0x02,
+            0xC0, 0x10,
+            // example.com A 93.184.216.34
+            0xC0, 0x10, 0x00, 0x01, 0x00, 0x01, 0x00, 0x00, 0x01, 0x2C, 0x00, 0x04,
+            0x5D, 0xB8, 0xD8, 0x22,
+            // example.com NS a.iana-servers.net
+            0xC0, 0x10, 0x00, 0x02, 0x00, 0x01, 0x00, 0x02, 0xA3, 0x00, 0x00, 0x14,
+            0x01, 0x61, 0x0C, 0x69, 0x61, 0x6E, 0x61, 0x2D, 0x73, 0x65, 0x72, 0x76,
+            0x65, 0x72, 0x73, 0x03, 0x6E, 0x65, 0x74, 0x00,
+        ];
+        assert_eq!(bytes, expected);
+        assert_eq!(DnsMessage::from_bytes(&bytes).unwrap(), message);
+    }
+
+    #[test]
+    fn test_dns_message_to_bytes_compresses_rdata_names() {
+        let message = DnsMessage {
+            header: DnsHeader::default(),
+            questions: vec![DnsQuestion {
+                qname: name("example.com"),
+                qtype: 15,
+                qclass: 1,
+            }],
+            answers: vec![
+                DnsAnswer {
+                    name: name("EXAMPLE.com"),
+                    qtype: 15,
+                    qclass: 1,
+                    ttl: 60,
+                    rdata: RData::Mx {
+                        preference: 10,
+                        exchange: name("mail.example.com"),
+                    },
+                },
+                DnsAnswer {
+                    name: name("_sip._udp.example.com"),
+                    qtype: 33,
+                    qclass: 1,
+                    ttl: 60,
+                    rdata: RData::Srv {
+                        priority: 0,
+                        weight: 0,
+                        port: 5060,
+                        target: name("mail.example.com"),
+                    },
+                },
+            ],
+            authorities: Vec::new(),
+            additionals: Vec::new(),
+            edns: None,
+        };
+        let bytes = message.to_bytes();
+        #[rustfmt::skip]
+        let expected = vec![
+            // header
+            0x04, 0xD2, 0x80, 0x00, 0x00, 0x01, 0x00, 0x02, 0x00, 0x00, 0x00, 0x00,
+            // question, example.com at 12, com at 20
+            0x07, 0x65, 0x78, 0x61, 0x6D, 0x70, 0x6C, 0x65, 0x03, 0x63, 0x6F, 0x6D,
+            0x00, 0x00, 0x0F, 0x00, 0x01,
+            // owner matches case-insensitively, exchange is "mail" + pointer
+            0xC0, 0x0C, 0x00, 0x0F, 0x00, 0x01, 0x00, 0x00, 0x00, 0x3C, 0x00, 0x09,
+            0x00, 0x0A, 0x04, 0x6D, 0x61, 0x69, 0x6C, 0xC0, 0x0C,
+            // SRV owner is compressed but its target never is
+            0x04, 0x5F, 0x73, 0x69, 0x70, 0x04, 0x5F, 0x75, 0x64, 0x70, 0xC0, 0x0C,
+            0x00, 0x21, 0x00, 0x01, 0x00, 0x00, 0x00, 0x3C, 0x00, 0x18,
+            0x00, 0x00, 0x00, 0x00, 0x13, 0xC4,
+            0x04, 0x6D, 0x61, 0x69, 0x6C, 0x07, 0x65, 0x78, 0x61, 0x6D, 0x70, 0x6C,
+            0x65, 0x03, 0x63, 0x6F, 0x6D, 0x00,
+        ];
+        assert_eq!(bytes, expected);
+    }
+
+    #[test]
+    fn test_read_name_forward_pointer() {
+        // the first name points ahead at the second, which points back at the first
+        let message_bytes = vec![0x01, 0x61, 0xC0, 0x04, 0x01, 0x62, 0xC0, 0x00];
+        let err = read_name(&message_bytes, &message_bytes).unwrap_err();
+        assert_eq!(
+            err,
+            DnsParseError::ForwardPointer {
+                offset: 2,
+                target: 4
+            }
+        );
+    }
+
+    #[test]
+    fn test_read_name_too_many_jumps() {
+        // "a" at 0, then a chain of pointers each pointing at the one before it
+        let mut message_bytes = vec![0x01, 0x61, 0x00, 0xC0, 0x00];
+        for offset in (3..60).step_by(2) {
+            message_bytes.extend_from_slice(&[0xC0, offset as u8]);
+        }
+        let (parsed, len) = read_name(&message_bytes[3..], &message_bytes).unwrap();
+        assert_eq!((parsed, len), (name("a"), 2));
+        let start = message_bytes.len() - 2;
+        let err = read_name(&message_bytes[start..], &message_bytes).unwrap_err();
+        assert_eq!(err, DnsParseError::PointerLoop { offset: 29 });
+    }
+
+    #[test]
+    fn test_read_name_too_long() {
+        // four 63 byte labels come to 256 bytes with their length bytes
+        let mut bytes = Vec::new();
+        for _ in 0..4 {
+            bytes.push(63);
+            bytes.extend_from_slice(&[b'a'; 63]);
+        }
+        bytes.push(0);
+        let err = read_name(&bytes, &bytes).unwrap_err();
+        assert_eq!(err, DnsParseError::NameTooLong { offset: 192 });
+
+        // 255 bytes including the terminator is fine
+        let mut bytes = Vec::new();
+        for len in [63, 63, 63, 61] {
+            bytes.push(len);
+            bytes.extend_from_slice(&vec![b'a'; len as usize]);
+        }
+        bytes.push(0);
+        let (parsed, len) = read_name(&bytes, &bytes).unwrap();
+        assert_eq!((parsed.label_count(), len), (4, 255));
+    }
+
+    fn a_record(owner: &str, last_octet: u8) -> DnsAnswer {
+        DnsAnswer {
+            name: name(owner),
+            qtype: 1,
+            qclass: 1,
+            ttl: 60,
+            rdata: RData::A(Ipv4Addr::new(192, 0, 2, last_octet)),
+        }
+    }
+
+    fn truncation_message() -> DnsMessage {
+        // each A record after the first for a.example is 16 bytes compressed
+        DnsMessage {
+            header: DnsHeader::default(),
+            questions: vec![DnsQuestion {
+                qname: name("a.example"),
+                qtype: 1,
+                qclass: 1,
+            }],
+            answers: vec![a_record("a.example", 1), a_record("a.example", 2)],
+            authorities: vec![a_record("b.example", 3)],
+            additionals: vec![a_record("c.example", 4), a_record("c.example", 5)],
+            edns: None,
+        }
+    }
+
+    #[test]
+    fn test_truncate_to_exact_fit() {
+        let mut message = truncation_message();
+        let full_len = message.to_bytes().len();
+        let bytes = message.truncate_to(full_len);
+        assert_eq!(bytes.len(), full_len);
+        assert!(!message.header.truncated_message);
+        assert_eq!(message, truncation_message());
+    }
+
+    #[test]
+    fn test_truncate_to_drops_additional_rrset_without_tc() {
+        let mut message = truncation_message();
+        let full_len = message.to_bytes().len();
+        // one byte short drops the whole c.example RRset, not just one record
+        let bytes = message.truncate_to(full_len - 1);
+        assert!(bytes.len() < full_len - 1);
+        assert!(message.additionals.is_empty());
+        assert_eq!(message.authorities.len(), 1);
+        assert!(!message.header.truncated_message);
+        assert_eq!(DnsMessage::from_bytes(&bytes).unwrap().additionals.len(), 0);
+    }
+
+    #[test]
+    fn test_truncate_to_drops_answers_with_tc() {
+        let mut message = truncation_message();
+        let question_only_len = DnsMessage {
+            answers: Vec::new(),
+            authorities: Vec::new(),
+            additionals: Vec::new(),
+            ..truncation_message()
+        }
+        .to_bytes()
+        .len();
+        let bytes = message.truncate_to(question_only_len + 1);
+        assert!(message.header.truncated_message);
+        assert!(message.answers.is_empty() && message.authorities.is_empty());
+        let parsed = DnsMessage::from_bytes(&bytes).unwrap();
+        assert!(parsed.header.truncated_message);
+        assert_eq!(parsed.questions.len(), 1);
+    }
+
+    #[test]
+    fn test_truncate_to_dropping_glue_sets_tc() {
+        let ns = |server: &str| DnsAnswer::new(name("sub.example"), 3600, RData::Ns(name(server)));
+        let referral = || DnsMessage {
+            answers: Vec::new(),
+            authorities: vec![ns("ns.sub.example"), ns("ns.elsewhere.test")],
+            additionals: vec![
+                a_record("ns.sub.example", 1),
+                a_record("ns.elsewhere.test", 2),
+            ],
+            ..truncation_message()
+        };
+
+        // the out-of-bailiwick server's address is only a hint
+        let mut message = referral();
+        let full_len = message.to_bytes().len();
+        let hint_dropped_len = message.truncate_to(full_len - 1).len();
+        assert_eq!(message.additionals, vec![a_record("ns.sub.example", 1)]);
+        assert!(!message.header.truncated_message);
+
+        // but ns.sub.example can't be resolved without its glue
+        let mut message = referral();
+        let bytes = message.truncate_to(hint_dropped_len - 1);
+        assert!(message.additionals.is_empty());
+        assert_eq!(message.authorities.len(), 2);
+        assert!(
+            DnsMessage::from_bytes(&bytes)
+                .unwrap()
+                .header
+                .truncated_message
+        );
+    }
+
+    #[test]
+    fn test_truncate_to_keeps_edns() {
+        let mut message = DnsMessage {
+            edns: Some(Edns::default()),
+            ..truncation_message()
+        };
+        let bytes = message.truncate_to(512.min(message.to_bytes().len() - 1));
+        let parsed = DnsMessage::from_bytes(&bytes).unwrap();
+        assert_eq!(parsed.edns, Some(Edns::default()));
+    }
+
+    #[test]
+    fn test_dns_message_reply_to() {
+        let mut query = DnsMessage::query(0xBEEF, DnsQuestion::new(name("example.com"), 1));
+        query.edns = Some(Edns {
+            udp_payload_size: 1232,
+            dnssec_ok: true,
+            ..Edns::default()
+        });
+        let query = DnsMessage::from_bytes(&query.to_bytes()).unwrap();
+        assert!(query.header.recursion_desired);
+        assert_eq!(query.questions[0].qclass, CLASS_IN);
+
+        let reply = DnsMessage::reply_to(&query);
+        assert_eq!(reply.header.id, 0xBEEF);
+        assert!(reply.header.response);
+        assert!(reply.header.recursion_desired);
+        assert_eq!(reply.questions, query.questions);
+        // our own payload size, but the DO bit echoed back
+        assert_eq!(
+            reply.edns,
+            Some(Edns {
+                dnssec_ok: true,
+                ..Edns::default()
+            })
+        );
+        assert_eq!(DnsMessage::reply_to(&DnsMessage::default()).edns, None);
+    }
+
+    #[test]
+    fn test_dns_answer_presentation_round_trip() {
+        let odd_name = DnsName::from_labels([&b"a.b c"[..], b"(x)", b"example"]).unwrap();
+        let records = [
+            (
+                "www.example.com. 300 IN A 192.0.2.1",
+                DnsAnswer::new(name("www.example.com"), 300, RData::A(Ipv4Addr::new(192, 0, 2, 1))),
+            ),
+            (
+                "www.example.com. 300 IN AAAA 2001:db8::1",
+                DnsAnswer::new(
+                    name("www.example.com"),
+                    300,
+                    RData::Aaaa("2001:db8::1".parse().unwrap()),
+                ),
+            ),
+            (
+                "example.com. 86400 IN NS ns1.example.com.",
+                DnsAnswer::new(name("example.com"), 86400, RData::Ns(name("ns1.example.com"))),
+            ),
+            (
+                "a\\.b\\032c.\\(x\\).example. 60 IN CNAME example.",
+                DnsAnswer::new(odd_name.clone(), 60, RData::Cname(name("example"))),
+            ),
+            (
+                "1.2.0.192.in-addr.arpa. 60 IN PTR a\\.b\\032c.\\(x\\).example.",
+                DnsAnswer::new(name("1.2.0.192.in-addr.arpa"), 60, RData::Ptr(odd_name)),
+            ),
+            (
+                "old.example.com. 3600 IN DNAME new.example.net.",
+                DnsAnswer::new(
+                    name("old.example.com"),
+                    3600,
+                    RData::Dname(name("new.example.net")),
+                ),
+            ),
+            (
+                "example.com. 3600 IN MX 10 mail.example.com.",
+                DnsAnswer::new(
+                    name("example.com"),
+                    3600,
+                    RData::Mx {
+                        preference: 10,
+                        exchange: name("mail.example.com"),
+                    },
+                ),
+            ),
+            (
+                "example.com. 3600 IN TXT \"v=spf1 -all\" \"say \\\"hi\\\" \\\\o/\" \"\\000\\255\" \"\"",
+                DnsAnswer::new(
+                    name("example.com"),
+                    3600,
+                    RData::Txt(vec![
+                        b"v=spf1 -all".to_vec(),
+                        b"say \"hi\" \\o/".to_vec(),
+                        vec![0, 255],
+                        Vec::new(),
+                    ]),
+                ),
+            ),
+            (
+                "example.com. 3600 IN SOA ns1.example.com. hostmaster.example.com. 2024010101 7200 900 1209600 300",
+                DnsAnswer::new(
+                    name("example.com"),
+                    3600,
+                    RData::Soa {
+                        mname: name("ns1.example.com"),
+                        rname: name("hostmaster.example.com"),
+                        serial: 2024010101,
+                        refresh: 7200,
+                        retry: 900,
+                        expire: 1209600,
+                        minimum: 300,
+                    },
+                ),
+            ),
+            (
+                "_sip._udp.example.com. 60 IN SRV 10 60 5060 sip.example.com.",
+                DnsAnswer::new(
+                    name("_sip._udp.example.com"),
+                    60,
+                    RData::Srv {
+                        priority: 10,
+                        weight: 60,
+                        port: 5060,
+                        target: name("sip.example.com"),
+                    },
+                ),
+            ),
+            (
+                "example.com. 60 IN CAA 128 issue \"ca.example.net; account=1\"",
+                DnsAnswer::new(
+                    name("example.com"),
+                    60,
+                    RData::Caa {
+                        flags: 128,
+                        tag: "issue".to_string(),
+                        value: b"ca.example.net; account=1".to_vec(),
+                    },
+                ),
+            ),
+            (
+                "example.com. 60 IN TYPE65280 \\# 3 0a0b0c",
+                DnsAnswer::new(
+                    name("example.com"),
+                    60,
+                    RData::Unknown {
+                        rtype: 65280,
+                        data: vec![10, 11, 12],
+                    },
+                ),
+            ),
+            (
+                "example.com. 60 IN TYPE65281 \\# 0",
+                DnsAnswer::new(
+                    name("example.com"),
+                    60,
+                    RData::Unknown {
+                        rtype: 65281,
+                        data: Vec::new(),
+                    },
+                ),
+            ),
+            // these have no text form that reads back the same
+            (
+                "example.com. 60 IN TXT \\# 0",
+                DnsAnswer::new(name("example.com"), 60, RData::Txt(Vec::new())),
+            ),
+            (
+                "example.com. 60 IN CAA \\# 4 00026121",
+                DnsAnswer::new(
+                    name("example.com"),
+                    60,
+                    RData::Caa {
+                        flags: 0,
+                        tag: "a!".to_string(),
+                        value: Vec::new(),
+                    },
+                ),
+            ),
+        ];
+        for (text, record) in records {
+            assert_eq!(record.to_string(), text);
+            assert_eq!(text.parse::<DnsAnswer>().unwrap(), record, "{}", text);
+            // and it's the same record on the wire
+            let wire = DnsAnswer::from_bytes(&record.to_bytes(), &record.to_bytes()).unwrap();
+            assert_eq!(wire, record, "{}", text);
+        }
+    }
+
+    #[test]
+    fn test_dns_answer_from_str_is_lenient() {
+        let record: DnsAnswer = "www.example.com 1h in a 192.0.2.1".parse().unwrap();
+        assert_eq!(record.to_string(), "www.example.com. 3600 IN A 192.0.2.1");
+        let record: DnsAnswer = "www. IN 60 A \\# 4 c0000201".parse().unwrap();
+        assert_eq!(record.to_string(), "www. 60 IN A 192.0.2.1");
+        assert!("www.example.com 60 A".parse::<DnsAnswer>().is_err());
+        assert!("www.example.com 60 CH A 192.0.2.1"
+            .parse::<DnsAnswer>()
+            .is_err());
+    }
 }
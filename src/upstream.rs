@@ -0,0 +1,586 @@
+//! Sending queries to upstream resolvers and waiting for their replies, and
+//! choosing between several of them.
+
+use std::fmt;
+use std::io::ErrorKind;
+use std::net::{SocketAddr, ToSocketAddrs, UdpSocket};
+use std::str::FromStr;
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::sync::Mutex;
+use std::time::{Duration, Instant};
+
+use crate::edns::SERVER_UDP_PAYLOAD_SIZE;
+use crate::error::UpstreamError;
+use crate::structs::{DnsMessage, RCODE_REFUSED, RCODE_SERVFAIL};
+
+/// How long to wait for each attempt when nothing else is configured.
+pub const DEFAULT_UPSTREAM_TIMEOUT: Duration = Duration::from_secs(2);
+/// Extra attempts after the first one times out.
+pub const DEFAULT_UPSTREAM_RETRIES: u32 = 2;
+/// Failures in a row before an upstream is marked down.
+const MAX_CONSECUTIVE_FAILURES: u32 = 3;
+/// How long a down upstream is skipped for, unless every one of them is down.
+const MARK_DOWN_FOR: Duration = Duration::from_secs(30);
+
+/// How to pick which upstream gets a query.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub enum Strategy {
+    /// Always the first healthy upstream in the configured order.
+    #[default]
+    Failover,
+    /// Each query starts at the next upstream along.
+    RoundRobin,
+    /// The healthy upstream with the lowest smoothed round trip time. Ones we
+    /// haven't heard from yet count as instant, so they all get tried.
+    LowestRtt,
+}
+
+impl FromStr for Strategy {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Strategy, String> {
+        match s {
+            "failover" => Ok(Strategy::Failover),
+            "round-robin" => Ok(Strategy::RoundRobin),
+            "lowest-rtt" => Ok(Strategy::LowestRtt),
+            _ => Err(format!(
+                "unknown upstream strategy {:?}, expected failover, round-robin or lowest-rtt",
+                s
+            )),
+        }
+    }
+}
+
+impl fmt::Display for Strategy {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str(match self {
+            Strategy::Failover => "failover",
+            Strategy::RoundRobin => "round-robin",
+            Strategy::LowestRtt => "lowest-rtt",
+        })
+    }
+}
+
+/// What we've seen of one upstream, as reported by [`UpstreamPool::stats`].
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct UpstreamStats {
+    pub address: String,
+    /// Queries sent to it, counting each attempt.
+    pub queries: u64,
+    /// Queries it served, i.e. whose reply went back to the client.
+    pub answered: u64,
+    /// Attempts that timed out or got SERVFAIL or REFUSED.
+    pub failures: u64,
+    /// Smoothed round trip time, once it has answered anything.
+    pub srtt: Option<Duration>,
+    pub consecutive_failures: u32,
+    pub down_until: Option<Instant>,
+}
+
+impl UpstreamStats {
+    pub fn is_down(&self, now: Instant) -> bool {
+        self.down_until.is_some_and(|until| now < until)
+    }
+}
+
+impl fmt::Display for UpstreamStats {
+    /// One line for logs, like `192.0.2.1:53 12 queries, 11 answered, 1 failed, srtt 20ms`.
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(
+            f,
+            "{} {} queries, {} answered, {} failed",
+            self.address, self.queries, self.answered, self.failures
+        )?;
+        if let Some(srtt) = self.srtt {
+            write!(f, ", srtt {}ms", srtt.as_millis())?;
+        }
+        if self.is_down(Instant::now()) {
+            f.write_str(", down")?;
+        }
+        Ok(())
+    }
+}
+
+/// A reply from the pool, along with which upstream it came from.
+#[derive(Debug, Clone)]
+pub struct UpstreamReply {
+    pub message: DnsMessage,
+    pub upstream: String,
+}
+
+/// A set of upstream resolvers queried according to a [`Strategy`], skipping any
+/// that have been failing lately.
+#[derive(Debug)]
+pub struct UpstreamPool {
+    strategy: Strategy,
+    stats: Mutex<Vec<UpstreamStats>>,
+    // where the next round robin query starts
+    next: AtomicUsize,
+}
+
+impl UpstreamPool {
+    pub fn new(addresses: &[String], strategy: Strategy) -> UpstreamPool {
+        let stats = addresses
+            .iter()
+            .map(|address| UpstreamStats {
+                address: address.clone(),
+                ..UpstreamStats::default()
+            })
+            .collect();
+        UpstreamPool {
+            strategy,
+            stats: Mutex::new(stats),
+            next: AtomicUsize::new(0),
+        }
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.stats.lock().unwrap().is_empty()
+    }
+
+    pub fn stats(&self) -> Vec<UpstreamStats> {
+        self.stats.lock().unwrap().clone()
+    }
+
+    /// Sends `query` to one upstream after another, in the order the strategy
+    /// picks, for up to `retries + 1` attempts or until `deadline`. SERVFAIL
+    /// and REFUSED count as failures worth trying elsewhere, but if nobody does
+    /// any better the last such reply is what comes back.
+    pub fn query(
+        &self,
+        query: &DnsMessage,
+        timeout: Duration,
+        retries: u32,
+        deadline: Instant,
+    ) -> Result<UpstreamReply, UpstreamError> {
+        let order = self.order(Instant::now());
+        let mut last_reply = None;
+        let mut attempts = 0;
+        while attempts <= retries && !order.is_empty() && Instant::now() < deadline {
+            let (index, address) = &order[attempts as usize % order.len()];
+            attempts += 1;
+            let start = Instant::now();
+            match exchange(address, query, timeout, deadline) {
+                Ok(message) if matches!(message.rcode(), RCODE_SERVFAIL | RCODE_REFUSED) => {
+                    self.record_failure(*index, Instant::now());
+                    let reply = UpstreamReply {
+                        message,
+                        upstream: address.clone(),
+                    };
+                    last_reply = Some((*index, reply));
+                }
+                Ok(message) => {
+                    self.record_success(*index, start.elapsed());
+                    return Ok(UpstreamReply {
+                        message,
+                        upstream: address.clone(),
+                    });
+                }
+                Err(e) => {
+                    eprintln!("No answer from upstream {}: {}", address, e);
+                    self.record_failure(*index, Instant::now());
+                }
+            }
+        }
+        match last_reply {
+            Some((index, reply)) => {
+                self.stats.lock().unwrap()[index].answered += 1;
+                Ok(reply)
+            }
+            None => Err(UpstreamError::Timeout { attempts }),
+        }
+    }
+
+    /// Upstreams to try, best first: the strategy's order, with any that are
+    /// marked down moved to the back.
+    fn order(&self, now: Instant) -> Vec<(usize, String)> {
+        let stats = self.stats.lock().unwrap();
+        let mut order: Vec<usize> = (0..stats.len()).collect();
+        match self.strategy {
+            Strategy::Failover => {}
+            Strategy::RoundRobin if !order.is_empty() => {
+                let start = self.next.fetch_add(1, Ordering::Relaxed) % order.len();
+                order.rotate_left(start);
+            }
+            Strategy::RoundRobin => {}
+            Strategy::LowestRtt => order.sort_by_key(|&i| stats[i].srtt.unwrap_or(Duration::ZERO)),
+        }
+        // a stable sort, so the strategy's order holds within each group
+        order.sort_by_key(|&i| stats[i].is_down(now));
+        order
+            .into_iter()
+            .map(|i| (i, stats[i].address.clone()))
+            .collect()
+    }
+
+    fn record_success(&self, index: usize, rtt: Duration) {
+        let mut stats = self.stats.lock().unwrap();
+        let upstream = &mut stats[index];
+        upstream.queries += 1;
+        upstream.answered += 1;
+        upstream.consecutive_failures = 0;
+        upstream.down_until = None;
+        // the same smoothing TCP uses (RFC 6298): 7/8 old, 1/8 new
+        upstream.srtt = Some(match upstream.srtt {
+            Some(srtt) => (srtt * 7 + rtt) / 8,
+            None => rtt,
+        });
+    }
+
+    fn record_failure(&self, index: usize, now: Instant) {
+        let mut stats = self.stats.lock().unwrap();
+        let upstream = &mut stats[index];
+        upstream.queries += 1;
+        upstream.failures += 1;
+        upstream.consecutive_failures += 1;
+        if upstream.consecutive_failures >= MAX_CONSECUTIVE_FAILURES && !upstream.is_down(now) {
+            eprintln!(
+                "Marking upstream {} down for {}s after {} failures in a row",
+                upstream.address,
+                MARK_DOWN_FOR.as_secs(),
+                upstream.consecutive_failures
+            );
+            upstream.down_until = Some(now + MARK_DOWN_FOR);
+        }
+    }
+}
+
+/// Sends `query` to the resolver at `address` once and returns its reply,
+/// waiting up to `timeout` but never past `deadline`. Trying again, here or
+/// elsewhere, is up to the caller.
+///
+/// To make spoofing a reply hard (RFC 5452) the query goes out with a random id
+/// from a fresh socket on a random ephemeral port, and the reply comes back with
+/// `query`'s own id put back. Anything that isn't a well-formed response to this
+/// exact query (matching id and question) is dropped and we keep waiting, since
+/// it's either stale or spoofed.
+pub fn exchange(
+    address: &str,
+    query: &DnsMessage,
+    timeout: Duration,
+    deadline: Instant,
+) -> Result<DnsMessage, UpstreamError> {
+    if Instant::now() >= deadline {
+        return Err(UpstreamError::Timeout { attempts: 0 });
+    }
+    let upstream = resolve(address)?;
+    let local: SocketAddr = if upstream.is_ipv4() {
+        "0.0.0.0:0".parse().unwrap()
+    } else {
+        "[::]:0".parse().unwrap()
+    };
+    let socket = UdpSocket::bind(local)?;
+    // a connected socket only hands us datagrams from the upstream itself
+    socket.connect(upstream)?;
+    let mut upstream_query = query.clone();
+    upstream_query.header.id = rand::random();
+    let query_bytes = upstream_query.to_bytes();
+    let mut buf = [0; SERVER_UDP_PAYLOAD_SIZE as usize];
+    socket.send(&query_bytes)?;
+    let attempt_deadline = deadline.min(Instant::now() + timeout);
+    loop {
+        let remaining = attempt_deadline.saturating_duration_since(Instant::now());
+        if remaining.is_zero() {
+            break;
+        }
+        socket.set_read_timeout(Some(remaining))?;
+        let size = match socket.recv(&mut buf) {
+            Ok(size) => size,
+            Err(e) if matches!(e.kind(), ErrorKind::WouldBlock | ErrorKind::TimedOut) => break,
+            // an ICMP unreachable: nobody is listening there
+            Err(e) if e.kind() == ErrorKind::ConnectionRefused => break,
+            Err(e) => return Err(e.into()),
+        };
+        match DnsMessage::from_bytes(&buf[..size]) {
+            Ok(mut reply) if is_reply_to(&reply, &upstream_query) => {
+                reply.header.id = query.header.id;
+                return Ok(reply);
+            }
+            Ok(reply) => eprintln!(
+                "Ignoring upstream reply with id {} that doesn't match the query",
+                reply.header.id
+            ),
+            Err(e) => eprintln!("Ignoring malformed upstream reply: {}", e),
+        }
+    }
+    Err(UpstreamError::Timeout { attempts: 1 })
+}
+
+fn resolve(address: &str) -> Result<SocketAddr, UpstreamError> {
+    address
+        .to_socket_addrs()?
+        .next()
+        .ok_or_else(|| UpstreamError::BadAddress {
+            address: address.to_string(),
+        })
+}
+
+/// Whether `reply` answers `query`: same id, marked as a response, and asking
+/// the same questions.
+fn is_reply_to(reply: &DnsMessage, query: &DnsMessage) -> bool {
+    reply.header.response
+        && reply.header.id == query.header.id
+        && reply.questions == query.questions
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::rdata::TYPE_A;
+    use crate::structs::DnsQuestion;
+    use std::thread;
+
+    fn query(id: u16) -> DnsMessage {
+        DnsMessage::query(id, DnsQuestion::new("example.com".parse().unwrap(), TYPE_A))
+    }
+
+    fn far_deadline() -> Instant {
+        Instant::now() + Duration::from_secs(60)
+    }
+
+    #[test]
+    fn test_exchange_skips_mismatched_replies() {
+        let resolver = UdpSocket::bind("127.0.0.1:0").unwrap();
+        let address = resolver.local_addr().unwrap().to_string();
+        thread::spawn(move || {
+            let mut buf = [0; 512];
+            let (size, source) = resolver.recv_from(&mut buf).unwrap();
+            let query = DnsMessage::from_bytes(&buf[..size]).unwrap();
+            // wrong id, then the wrong question, then garbage, then the real thing
+            let mut wrong_id = DnsMessage::reply_to(&query);
+            wrong_id.header.id ^= 1;
+            let mut wrong_question = DnsMessage::reply_to(&query);
+            wrong_question.questions[0].qname = "example.org".parse().unwrap();
+            resolver.send_to(&wrong_id.to_bytes(), source).unwrap();
+            resolver
+                .send_to(&wrong_question.to_bytes(), source)
+                .unwrap();
+            resolver.send_to(&[0xFF; 5], source).unwrap();
+            let mut reply = DnsMessage::reply_to(&query);
+            reply.header.recursion_available = true;
+            resolver.send_to(&reply.to_bytes(), source).unwrap();
+        });
+        let reply = exchange(&address, &query(77), Duration::from_secs(5), far_deadline()).unwrap();
+        assert_eq!(reply.header.id, 77);
+        assert!(reply.header.recursion_available);
+    }
+
+    #[test]
+    fn test_exchange_uses_random_ids() {
+        let resolver = UdpSocket::bind("127.0.0.1:0").unwrap();
+        let address = resolver.local_addr().unwrap().to_string();
+        let seen = thread::spawn(move || {
+            let mut buf = [0; 512];
+            let mut seen = Vec::new();
+            for _ in 0..4 {
+                let (size, source) = resolver.recv_from(&mut buf).unwrap();
+                let query = DnsMessage::from_bytes(&buf[..size]).unwrap();
+                seen.push((query.header.id, source.port()));
+                let reply = DnsMessage::reply_to(&query);
+                resolver.send_to(&reply.to_bytes(), source).unwrap();
+            }
+            seen
+        });
+        for _ in 0..4 {
+            let reply =
+                exchange(&address, &query(77), Duration::from_secs(5), far_deadline()).unwrap();
+            assert_eq!(reply.header.id, 77);
+        }
+        let seen = seen.join().unwrap();
+        // all four matching the client's id or reusing one port would be a
+        // one in 2^48 coincidence
+        assert!(seen.iter().any(|&(id, _)| id != 77));
+        assert!(seen.iter().any(|&(_, port)| port != seen[0].1));
+    }
+
+    #[test]
+    fn test_pool_retries_then_times_out() {
+        // a resolver that reads queries and never answers
+        let resolver = UdpSocket::bind("127.0.0.1:0").unwrap();
+        let address = resolver.local_addr().unwrap().to_string();
+        let counter = thread::spawn(move || {
+            resolver
+                .set_read_timeout(Some(Duration::from_millis(500)))
+                .unwrap();
+            let mut buf = [0; 512];
+            let mut received = 0;
+            while resolver.recv_from(&mut buf).is_ok() {
+                received += 1;
+            }
+            received
+        });
+        // exchange itself only ever sends once, the pool does the retrying
+        let err = exchange(
+            &address,
+            &query(1),
+            Duration::from_millis(50),
+            far_deadline(),
+        )
+        .unwrap_err();
+        assert!(matches!(err, UpstreamError::Timeout { attempts: 1 }));
+        let pool = pool(&[&address], Strategy::Failover);
+        let err =
+            (pool.query(&query(1), Duration::from_millis(50), 2, far_deadline())).unwrap_err();
+        assert!(matches!(err, UpstreamError::Timeout { attempts: 3 }));
+        assert_eq!(counter.join().unwrap(), 4);
+    }
+
+    #[test]
+    fn test_exchange_stops_at_deadline() {
+        let resolver = UdpSocket::bind("127.0.0.1:0").unwrap();
+        let address = resolver.local_addr().unwrap().to_string();
+        let start = Instant::now();
+        // a long timeout, but the overall deadline comes first
+        let err = exchange(
+            &address,
+            &query(1),
+            Duration::from_secs(10),
+            start + Duration::from_millis(250),
+        )
+        .unwrap_err();
+        assert!(matches!(err, UpstreamError::Timeout { attempts: 1 }));
+        assert!(start.elapsed() < Duration::from_millis(400));
+    }
+
+    /// A resolver answering every query with `rcode`.
+    fn start_resolver(rcode: u16) -> String {
+        let resolver = UdpSocket::bind("127.0.0.1:0").unwrap();
+        let address = resolver.local_addr().unwrap().to_string();
+        thread::spawn(move || {
+            let mut buf = [0; 512];
+            while let Ok((size, source)) = resolver.recv_from(&mut buf) {
+                let query = DnsMessage::from_bytes(&buf[..size]).unwrap();
+                let mut reply = DnsMessage::reply_to(&query);
+                reply.set_rcode(rcode);
+                resolver.send_to(&reply.to_bytes(), source).unwrap();
+            }
+        });
+        address
+    }
+
+    fn pool(addresses: &[&str], strategy: Strategy) -> UpstreamPool {
+        let addresses: Vec<String> = addresses.iter().map(|a| a.to_string()).collect();
+        UpstreamPool::new(&addresses, strategy)
+    }
+
+    fn order(pool: &UpstreamPool, now: Instant) -> Vec<usize> {
+        pool.order(now).into_iter().map(|(i, _)| i).collect()
+    }
+
+    #[test]
+    fn test_strategy_from_str() {
+        for strategy in [
+            Strategy::Failover,
+            Strategy::RoundRobin,
+            Strategy::LowestRtt,
+        ] {
+            assert_eq!(strategy.to_string().parse(), Ok(strategy));
+        }
+        assert!("random".parse::<Strategy>().is_err());
+    }
+
+    #[test]
+    fn test_pool_order_by_strategy() {
+        let now = Instant::now();
+        let failover = pool(&["a:53", "b:53", "c:53"], Strategy::Failover);
+        assert_eq!(order(&failover, now), [0, 1, 2]);
+        assert_eq!(order(&failover, now), [0, 1, 2]);
+
+        let round_robin = pool(&["a:53", "b:53", "c:53"], Strategy::RoundRobin);
+        assert_eq!(order(&round_robin, now), [0, 1, 2]);
+        assert_eq!(order(&round_robin, now), [1, 2, 0]);
+        assert_eq!(order(&round_robin, now), [2, 0, 1]);
+
+        let lowest_rtt = pool(&["a:53", "b:53", "c:53"], Strategy::LowestRtt);
+        lowest_rtt.record_success(0, Duration::from_millis(80));
+        lowest_rtt.record_success(1, Duration::from_millis(20));
+        // c hasn't answered yet so it gets a turn first
+        assert_eq!(order(&lowest_rtt, now), [2, 1, 0]);
+    }
+
+    #[test]
+    fn test_upstream_stats_display() {
+        let mut stats = UpstreamStats {
+            address: "192.0.2.1:53".to_string(),
+            queries: 12,
+            answered: 11,
+            failures: 1,
+            ..UpstreamStats::default()
+        };
+        assert_eq!(
+            stats.to_string(),
+            "192.0.2.1:53 12 queries, 11 answered, 1 failed"
+        );
+        stats.srtt = Some(Duration::from_micros(20_400));
+        stats.down_until = Some(Instant::now() + MARK_DOWN_FOR);
+        assert_eq!(
+            stats.to_string(),
+            "192.0.2.1:53 12 queries, 11 answered, 1 failed, srtt 20ms, down"
+        );
+    }
+
+    #[test]
+    fn test_pool_marks_failing_upstreams_down() {
+        let now = Instant::now();
+        let pool = pool(&["a:53", "b:53"], Strategy::Failover);
+        for _ in 0..MAX_CONSECUTIVE_FAILURES - 1 {
+            pool.record_failure(0, now);
+        }
+        assert_eq!(order(&pool, now), [0, 1]);
+        pool.record_failure(0, now);
+        assert!(pool.stats()[0].is_down(now));
+        assert_eq!(order(&pool, now), [1, 0]);
+        // back once the time is up, or as soon as it answers anything
+        assert_eq!(order(&pool, now + MARK_DOWN_FOR), [0, 1]);
+        pool.record_success(0, Duration::from_millis(10));
+        assert_eq!(order(&pool, now), [0, 1]);
+        let stats = &pool.stats()[0];
+        assert_eq!(
+            (stats.queries, stats.answered, stats.failures),
+            (
+                MAX_CONSECUTIVE_FAILURES as u64 + 1,
+                1,
+                MAX_CONSECUTIVE_FAILURES as u64
+            )
+        );
+    }
+
+    #[test]
+    fn test_pool_smooths_rtt() {
+        let pool = pool(&["a:53"], Strategy::LowestRtt);
+        pool.record_success(0, Duration::from_millis(80));
+        assert_eq!(pool.stats()[0].srtt, Some(Duration::from_millis(80)));
+        pool.record_success(0, Duration::from_millis(160));
+        assert_eq!(pool.stats()[0].srtt, Some(Duration::from_millis(90)));
+    }
+
+    #[test]
+    fn test_pool_fails_over() {
+        // bound but never answering, then one that refuses, then a working one
+        let silent = UdpSocket::bind("127.0.0.1:0").unwrap();
+        let silent = silent.local_addr().unwrap().to_string();
+        let refusing = start_resolver(RCODE_REFUSED);
+        let working = start_resolver(0);
+        let pool = UpstreamPool::new(
+            &[silent.clone(), refusing.clone(), working.clone()],
+            Strategy::Failover,
+        );
+        let reply = pool
+            .query(&query(5), Duration::from_millis(50), 2, far_deadline())
+            .unwrap();
+        assert_eq!(reply.upstream, working);
+        assert_eq!(reply.message.rcode(), 0);
+        let answered: Vec<(u64, u64)> = (pool.stats().iter())
+            .map(|stats| (stats.answered, stats.failures))
+            .collect();
+        assert_eq!(answered, [(0, 1), (0, 1), (1, 0)]);
+
+        // with nobody better to ask, the refusal is passed along
+        let pool = UpstreamPool::new(&[silent, refusing.clone()], Strategy::Failover);
+        let reply = pool
+            .query(&query(6), Duration::from_millis(50), 1, far_deadline())
+            .unwrap();
+        assert_eq!(reply.upstream, refusing);
+        assert_eq!(reply.message.rcode(), RCODE_REFUSED);
+    }
+}
@@ -0,0 +1,509 @@
+//! Zones we answer for with authority, loaded from master files.
+
+use std::collections::BTreeMap;
+use std::ops::Bound;
+use std::path::Path;
+
+use crate::error::ZoneError;
+use crate::master::read_master_file;
+use crate::name::DnsName;
+use crate::rdata::{RData, TYPE_A, TYPE_AAAA, TYPE_CNAME, TYPE_DNAME, TYPE_NS, TYPE_SOA};
+use crate::structs::DnsAnswer;
+
+/// What a zone has to say about a question.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum Lookup {
+    /// The records of the asked type, or the CNAME the name is an alias with.
+    /// Below a DNAME it's the DNAME and the CNAME made from it.
+    Answer(Vec<DnsAnswer>),
+    /// The name exists but has nothing of the asked type.
+    NoData,
+    /// The name doesn't exist, and neither does anything below it.
+    NxDomain,
+    /// The name is below a DNAME, but swapping in its target makes the name
+    /// too long. Holds the DNAME.
+    YxDomain(Vec<DnsAnswer>),
+    /// The name is at or below a zone cut, so all we can say is which servers
+    /// have the child zone, plus the addresses we have for them (the glue).
+    Referral {
+        ns: Vec<DnsAnswer>,
+        glue: Vec<DnsAnswer>,
+    },
+}
+
+#[derive(Debug, Clone)]
+pub struct Zone {
+    origin: DnsName,
+    // canonical order, so everything below a name comes straight after it
+    records: BTreeMap<DnsName, Vec<DnsAnswer>>,
+}
+
+impl Zone {
+    /// A zone holding `records`, which have to include exactly one SOA at the
+    /// origin and nothing outside it.
+    pub fn new(origin: DnsName, records: Vec<DnsAnswer>) -> Result<Zone, ZoneError> {
+        let invalid = |message: String| ZoneError::Invalid {
+            origin: origin.to_string(),
+            message,
+        };
+        let mut by_name: BTreeMap<DnsName, Vec<DnsAnswer>> = BTreeMap::new();
+        for record in records {
+            if !record.name.is_subdomain_of(&origin) {
+                return Err(invalid(format!("{} is outside the zone", record.name)));
+            }
+            let existing = by_name.entry(record.name.clone()).or_default();
+            if !existing.contains(&record) {
+                existing.push(record);
+            }
+        }
+        let soas = (by_name.get(&origin).into_iter().flatten())
+            .filter(|record| record.qtype == TYPE_SOA)
+            .count();
+        if soas != 1 {
+            let message = format!("needs one SOA record at its origin, found {}", soas);
+            return Err(invalid(message));
+        }
+        Ok(Zone {
+            origin,
+            records: by_name,
+        })
+    }
+
+    /// Reads the zone for `origin` from a master file.
+    pub fn load(path: &Path, origin: DnsName) -> Result<Zone, ZoneError> {
+        let records = read_master_file(path, &origin)?;
+        Zone::new(origin, records)
+    }
+
+    pub fn origin(&self) -> &DnsName {
+        &self.origin
+    }
+
+    pub fn soa(&self) -> &DnsAnswer {
+        (self.records[&self.origin].iter())
+            .find(|record| record.qtype == TYPE_SOA)
+            .expect("Zone::new checks there's an SOA")
+    }
+
+    /// The SOA for the authority section of a negative answer, with its TTL
+    /// capped at the SOA minimum as RFC 2308 3 asks.
+    pub fn negative_soa(&self) -> DnsAnswer {
+        let mut soa = self.soa().clone();
+        if let RData::Soa { minimum, .. } = soa.rdata {
+            soa.ttl = soa.ttl.min(minimum);
+        }
+        soa
+    }
+
+    /// Looks `qname` up, which has to be at or below the origin. Names that
+    /// don't exist can still be answered by a wildcard (RFC 4592).
+    pub fn lookup(&self, qname: &DnsName, qtype: u16) -> Lookup {
+        // going down from the origin, the first zone cut or DNAME on the way to
+        // the name decides the answer, whatever's below it
+        for count in self.origin.label_count()..=qname.label_count() {
+            let name = qname.suffix(count);
+            let Some(records) = self.records.get(&name) else {
+                continue;
+            };
+            if name != self.origin && records.iter().any(|record| record.qtype == TYPE_NS) {
+                return self.referral(records);
+            }
+            let dname = records.iter().find(|record| record.qtype == TYPE_DNAME);
+            if let Some(dname) = dname.filter(|_| name != *qname) {
+                return substitute(dname, qname);
+            }
+        }
+        if let Some(records) = self.records.get(qname) {
+            return select(records, qtype, qname);
+        }
+        if self.has_descendants(qname) {
+            // an empty non-terminal exists, it just has no records of its own
+            return Lookup::NoData;
+        }
+        // the closest encloser is the nearest ancestor that exists, and only a
+        // wildcard directly below it can match (RFC 4592 3.3.1)
+        let encloser = ((self.origin.label_count()..qname.label_count()).rev())
+            .map(|count| qname.suffix(count))
+            .find(|name| self.exists(name))
+            .expect("the origin always exists");
+        let source = (encloser.child(b"*")).expect("no longer than qname, which has a label more");
+        match self.records.get(&source) {
+            Some(records) => select(records, qtype, qname),
+            None if self.has_descendants(&source) => Lookup::NoData,
+            None => Lookup::NxDomain,
+        }
+    }
+
+    /// The NS records at a zone cut, with the addresses for any of the servers
+    /// named that are inside this zone.
+    fn referral(&self, records: &[DnsAnswer]) -> Lookup {
+        let ns: Vec<DnsAnswer> = (records.iter())
+            .filter(|record| record.qtype == TYPE_NS)
+            .cloned()
+            .collect();
+        let glue = (ns.iter())
+            .filter_map(|record| match &record.rdata {
+                RData::Ns(server) => self.records.get(server),
+                _ => None,
+            })
+            .flatten()
+            .filter(|record| record.qtype == TYPE_A || record.qtype == TYPE_AAAA)
+            .cloned()
+            .collect();
+        Lookup::Referral { ns, glue }
+    }
+
+    /// Whether `name` has records or anything below it does.
+    fn exists(&self, name: &DnsName) -> bool {
+        self.records.contains_key(name) || self.has_descendants(name)
+    }
+
+    /// Whether any name below `name` has records.
+    fn has_descendants(&self, name: &DnsName) -> bool {
+        (self.records)
+            .range::<DnsName, _>((Bound::Excluded(name), Bound::Unbounded))
+            .next()
+            .is_some_and(|(next, _)| next.is_subdomain_of(name))
+    }
+}
+
+/// The records at a name for `qtype`, or its CNAME if it has one, owned by
+/// `qname`, which differs from their own name when they come from a wildcard.
+fn select(records: &[DnsAnswer], qtype: u16, qname: &DnsName) -> Lookup {
+    let of_type = |rtype| -> Vec<DnsAnswer> {
+        (records.iter())
+            .filter(|record| record.qtype == rtype)
+            .map(|record| DnsAnswer {
+                name: qname.clone(),
+                ..record.clone()
+            })
+            .collect()
+    };
+    let matching = of_type(qtype);
+    if !matching.is_empty() {
+        return Lookup::Answer(matching);
+    }
+    let cname = of_type(TYPE_CNAME);
+    if !cname.is_empty() {
+        return Lookup::Answer(cname);
+    }
+    Lookup::NoData
+}
+
+/// The DNAME and a CNAME from `qname` to the same name below the DNAME's
+/// target (RFC 6672 3.3).
+fn substitute(dname: &DnsAnswer, qname: &DnsName) -> Lookup {
+    let RData::Dname(target) = &dname.rdata else {
+        unreachable!("only called with DNAME records");
+    };
+    let prefix = qname
+        .labels()
+        .take(qname.label_count() - dname.name.label_count());
+    match DnsName::from_labels(prefix.chain(target.labels())) {
+        Ok(alias) => Lookup::Answer(vec![
+            dname.clone(),
+            DnsAnswer::new(qname.clone(), dname.ttl, RData::Cname(alias)),
+        ]),
+        Err(_) => Lookup::YxDomain(vec![dname.clone()]),
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::master::parse_master;
+    use crate::rdata::{TYPE_A, TYPE_MX, TYPE_SRV, TYPE_TXT};
+
+    fn name(s: &str) -> DnsName {
+        s.parse().unwrap()
+    }
+
+    fn zone(text: &str) -> Zone {
+        let origin = name("example.com");
+        Zone::new(origin.clone(), parse_master(text, &origin).unwrap()).unwrap()
+    }
+
+    const ZONE: &str = "
+$TTL 3600
+@        SOA  ns1 hostmaster 1 7200 900 1209600 300
+         NS   ns1
+ns1      A    192.0.2.1
+www      A    192.0.2.10
+         A    192.0.2.11
+alias    CNAME www
+a.b.deep TXT  \"below an empty non-terminal\"
+";
+
+    #[test]
+    fn test_zone_lookup() {
+        let zone = zone(ZONE);
+        let Lookup::Answer(records) = zone.lookup(&name("WWW.example.com"), TYPE_A) else {
+            panic!("expected an answer");
+        };
+        assert_eq!(records.len(), 2);
+        assert_eq!(
+            zone.lookup(&name("alias.example.com"), TYPE_A),
+            Lookup::Answer(vec![DnsAnswer::new(
+                name("alias.example.com"),
+                3600,
+                RData::Cname(name("www.example.com"))
+            )])
+        );
+        assert_eq!(
+            zone.lookup(&name("www.example.com"), TYPE_MX),
+            Lookup::NoData
+        );
+        assert_eq!(
+            zone.lookup(&name("nope.example.com"), TYPE_A),
+            Lookup::NxDomain
+        );
+        assert_eq!(
+            zone.lookup(&name("x.www.example.com"), TYPE_A),
+            Lookup::NxDomain
+        );
+    }
+
+    #[test]
+    fn test_zone_empty_non_terminals_exist() {
+        let zone = zone(ZONE);
+        assert_eq!(
+            zone.lookup(&name("b.deep.example.com"), TYPE_A),
+            Lookup::NoData
+        );
+        assert_eq!(
+            zone.lookup(&name("deep.example.com"), TYPE_TXT),
+            Lookup::NoData
+        );
+        assert!(matches!(
+            zone.lookup(&name("a.b.deep.example.com"), TYPE_TXT),
+            Lookup::Answer(_)
+        ));
+        assert_eq!(
+            zone.lookup(&name("c.deep.example.com"), TYPE_A),
+            Lookup::NxDomain
+        );
+    }
+
+    #[test]
+    fn test_zone_negative_soa_ttl() {
+        let soa = zone(ZONE).negative_soa();
+        assert_eq!(soa.name, name("example.com"));
+        assert_eq!(soa.ttl, 300);
+    }
+
+    #[test]
+    fn test_zone_needs_one_soa_and_in_zone_names() {
+        let origin = name("example.com");
+        let records = |text| parse_master(text, &origin).unwrap();
+        assert!(matches!(
+            Zone::new(origin.clone(), records("www 60 A 192.0.2.1")),
+            Err(ZoneError::Invalid { .. })
+        ));
+        assert!(matches!(
+            Zone::new(
+                origin.clone(),
+                records("@ 60 SOA ns hm 1 2 3 4 5\nother.org. 60 A 192.0.2.1")
+            ),
+            Err(ZoneError::Invalid { .. })
+        ));
+    }
+
+    /// The example zone from RFC 4592 2.2.1.
+    const WILDCARD_ZONE: &str = "
+$ORIGIN example.
+$TTL 3600
+@                 SOA   ns.example.com. hm.example.com. 1 2 3 4 300
+                  NS    ns.example.com.
+*                 TXT   \"this is a wildcard\"
+*                 MX    10 host1
+sub.*             TXT   \"this is not a wildcard\"
+host1             A     192.0.2.1
+_ssh._tcp.host1   SRV   0 0 22 host1
+_ssh._tcp.host2   SRV   0 0 22 host2
+*.alias           CNAME host1
+subdel            NS    ns.example.com.
+subdel            NS    ns.example.net.
+";
+
+    fn wildcard_zone() -> Zone {
+        let origin = name("example");
+        Zone::new(
+            origin.clone(),
+            parse_master(WILDCARD_ZONE, &origin).unwrap(),
+        )
+        .unwrap()
+    }
+
+    #[test]
+    fn test_zone_wildcard_synthesis() {
+        let zone = wildcard_zone();
+        // the examples from RFC 4592 2.2.1 that a wildcard does answer
+        let Lookup::Answer(records) = zone.lookup(&name("host3.example"), TYPE_MX) else {
+            panic!("expected the wildcard MX");
+        };
+        assert_eq!(
+            records,
+            vec![DnsAnswer::new(
+                name("host3.example"),
+                3600,
+                RData::Mx {
+                    preference: 10,
+                    exchange: name("host1.example"),
+                }
+            )]
+        );
+        assert_eq!(zone.lookup(&name("host3.example"), TYPE_A), Lookup::NoData);
+        let Lookup::Answer(records) = zone.lookup(&name("foo.bar.example"), TYPE_TXT) else {
+            panic!("expected the wildcard TXT");
+        };
+        assert_eq!(records[0].name, name("foo.bar.example"));
+        // a wildcard CNAME is an alias like any other
+        let Lookup::Answer(records) = zone.lookup(&name("www.alias.example"), TYPE_A) else {
+            panic!("expected the wildcard CNAME");
+        };
+        assert_eq!(records[0].name, name("www.alias.example"));
+        assert_eq!(records[0].rdata, RData::Cname(name("host1.example")));
+        // asking for the wildcard itself is just an exact match
+        assert!(matches!(
+            zone.lookup(&name("*.example"), TYPE_MX),
+            Lookup::Answer(_)
+        ));
+    }
+
+    #[test]
+    fn test_zone_wildcard_closest_encloser() {
+        let zone = wildcard_zone();
+        // names that exist are never answered from the wildcard
+        assert_eq!(zone.lookup(&name("host1.example"), TYPE_MX), Lookup::NoData);
+        assert_eq!(zone.lookup(&name("sub.*.example"), TYPE_MX), Lookup::NoData);
+        // and nor are empty non-terminals
+        assert_eq!(
+            zone.lookup(&name("_tcp.host1.example"), TYPE_TXT),
+            Lookup::NoData
+        );
+        // the closest encloser is _tcp.host1.example, with no wildcard below it
+        assert_eq!(
+            zone.lookup(&name("_telnet._tcp.host1.example"), TYPE_SRV),
+            Lookup::NxDomain
+        );
+        // the closest encloser is *.example itself, and *.*.example doesn't exist
+        assert_eq!(
+            zone.lookup(&name("ghost.*.example"), TYPE_MX),
+            Lookup::NxDomain
+        );
+        // nor names below a zone cut
+        assert!(matches!(
+            zone.lookup(&name("host.subdel.example"), TYPE_A),
+            Lookup::Referral { .. }
+        ));
+        // host1.example exists, so *.example doesn't cover names below it
+        assert_eq!(
+            zone.lookup(&name("deeper.host1.example"), TYPE_TXT),
+            Lookup::NxDomain
+        );
+    }
+
+    #[test]
+    fn test_zone_dname_substitution() {
+        let origin = name("example.com");
+        let long_label = "a".repeat(63);
+        let text = format!(
+            "$TTL 60\n@ SOA ns hm 1 2 3 4 5\nold DNAME new.example.net.\n\
+             long DNAME {0}.{0}.{0}.example.net.",
+            long_label
+        );
+        let zone = Zone::new(origin.clone(), parse_master(&text, &origin).unwrap()).unwrap();
+        let dname = DnsAnswer::new(
+            name("old.example.com"),
+            60,
+            RData::Dname(name("new.example.net")),
+        );
+        assert_eq!(
+            zone.lookup(&name("www.Old.example.com"), TYPE_A),
+            Lookup::Answer(vec![
+                dname.clone(),
+                DnsAnswer::new(
+                    name("www.Old.example.com"),
+                    60,
+                    RData::Cname(name("www.new.example.net"))
+                ),
+            ])
+        );
+        // the DNAME owner itself isn't redirected
+        assert_eq!(
+            zone.lookup(&name("old.example.com"), TYPE_A),
+            Lookup::NoData
+        );
+        assert_eq!(
+            zone.lookup(&name("old.example.com"), TYPE_DNAME),
+            Lookup::Answer(vec![dname])
+        );
+        let too_long = format!("{0}.{0}.long.example.com", long_label);
+        assert!(matches!(
+            zone.lookup(&name(&too_long), TYPE_A),
+            Lookup::YxDomain(_)
+        ));
+    }
+
+    const DELEGATING_ZONE: &str = "
+$TTL 3600
+@               SOA   ns1 hostmaster 1 7200 900 1209600 300
+                NS    ns1
+ns1             A     192.0.2.1
+; sub.example.com is delegated, with glue for the server inside it
+sub             NS    ns1.sub
+                NS    ns.example.net.
+ns1.sub         A     192.0.2.53
+                AAAA  2001:db8::53
+; below the cut, so never answered from here
+deeper.sub      NS    ns.deeper.sub
+www.sub         A     192.0.2.80
+; a cut under an empty non-terminal, served by a name elsewhere in the zone
+lab.branch      NS    ns1
+";
+
+    #[test]
+    fn test_zone_referrals() {
+        let zone = zone(DELEGATING_ZONE);
+        let referral = |qname: &str, qtype| match zone.lookup(&name(qname), qtype) {
+            Lookup::Referral { ns, glue } => {
+                let ns: Vec<String> = ns.iter().map(|r| r.rdata.to_string()).collect();
+                let glue: Vec<String> = glue.iter().map(|r| r.to_string()).collect();
+                (ns, glue)
+            }
+            other => panic!("expected a referral for {}, got {:?}", qname, other),
+        };
+        let sub = (
+            vec![
+                "ns1.sub.example.com.".to_string(),
+                "ns.example.net.".to_string(),
+            ],
+            vec![
+                "ns1.sub.example.com. 3600 IN A 192.0.2.53".to_string(),
+                "ns1.sub.example.com. 3600 IN AAAA 2001:db8::53".to_string(),
+            ],
+        );
+        // at the cut, below it, and even for the cut's own NS records
+        assert_eq!(referral("sub.example.com", TYPE_A), sub);
+        assert_eq!(referral("www.sub.example.com", TYPE_A), sub);
+        assert_eq!(referral("sub.example.com", TYPE_NS), sub);
+        // the topmost cut wins over anything deeper
+        assert_eq!(referral("x.deeper.sub.example.com", TYPE_A), sub);
+        assert_eq!(
+            referral("host.lab.branch.example.com", TYPE_A),
+            (
+                vec!["ns1.example.com.".to_string()],
+                vec!["ns1.example.com. 3600 IN A 192.0.2.1".to_string()]
+            )
+        );
+        // the apex NS records are ours, not a cut
+        assert!(matches!(
+            zone.lookup(&name("example.com"), TYPE_NS),
+            Lookup::Answer(_)
+        ));
+        assert_eq!(
+            zone.lookup(&name("branch.example.com"), TYPE_A),
+            Lookup::NoData
+        );
+    }
+}
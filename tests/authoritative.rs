@@ -0,0 +1,117 @@
+//! A zone loaded from a master file and served over UDP.
+
+use std::net::{SocketAddr, UdpSocket};
+use std::path::Path;
+use std::sync::Arc;
+use std::thread;
+use std::time::Duration;
+
+use dns_starter_rust::server::{serve_udp, Server, ServerConfig};
+use dns_starter_rust::zone::Zone;
+use dns_starter_rust::{
+    DnsMessage, DnsQuestion, RData, RCODE_NOERROR, RCODE_NXDOMAIN, TYPE_A, TYPE_AAAA, TYPE_MX,
+    TYPE_SRV, TYPE_TXT,
+};
+
+fn start_server() -> SocketAddr {
+    let zone = Zone::load(
+        Path::new("tests/zones/example.com.zone"),
+        "example.com".parse().unwrap(),
+    )
+    .unwrap();
+    let config = ServerConfig {
+        zones: vec![zone],
+        ..ServerConfig::default()
+    };
+    let socket = UdpSocket::bind("127.0.0.1:0").unwrap();
+    let addr = socket.local_addr().unwrap();
+    thread::spawn(move || serve_udp(socket, Arc::new(Server::new(config))));
+    addr
+}
+
+fn exchange(server: SocketAddr, qname: &str, qtype: u16) -> DnsMessage {
+    let query = DnsMessage::query(0x4242, DnsQuestion::new(qname.parse().unwrap(), qtype));
+    let client = UdpSocket::bind("127.0.0.1:0").unwrap();
+    client
+        .set_read_timeout(Some(Duration::from_secs(5)))
+        .unwrap();
+    client.send_to(&query.to_bytes(), server).unwrap();
+    let mut buf = [0; 4096];
+    let (size, _) = client.recv_from(&mut buf).unwrap();
+    let reply = DnsMessage::from_bytes(&buf[..size]).unwrap();
+    assert_eq!(reply.header.id, 0x4242);
+    assert!(reply.header.authoritative_answer);
+    reply
+}
+
+#[test]
+fn test_records_from_the_zone_file() {
+    let server = start_server();
+    let reply = exchange(server, "example.com", TYPE_MX);
+    assert_eq!(reply.rcode(), RCODE_NOERROR);
+    assert_eq!(reply.answers.len(), 1);
+    assert_eq!(reply.answers[0].ttl, 3600);
+    assert_eq!(
+        reply.answers[0].rdata,
+        RData::Mx {
+            preference: 10,
+            exchange: "mail.example.com".parse().unwrap(),
+        }
+    );
+
+    // these come from the $INCLUDEd file, relative to its own origin
+    let reply = exchange(server, "web.hosts.example.com", TYPE_A);
+    assert_eq!(reply.answers[0].ttl, 300);
+    // with no TTL of its own a record gets $TTL, not the one above it
+    let reply = exchange(server, "web.hosts.example.com", TYPE_AAAA);
+    assert_eq!(reply.answers.len(), 1);
+    assert_eq!(reply.answers[0].ttl, 3600);
+    assert_eq!(
+        reply.answers[0].rdata,
+        RData::Aaaa("2001:db8::80".parse().unwrap())
+    );
+    let reply = exchange(server, "web.hosts.example.com", TYPE_TXT);
+    assert_eq!(
+        reply.answers[0].rdata,
+        RData::Txt(vec![b"web server".to_vec(), b"rack 4".to_vec()])
+    );
+}
+
+#[test]
+fn test_negative_answers_carry_the_soa() {
+    let server = start_server();
+    let reply = exchange(server, "nope.example.com", TYPE_A);
+    assert_eq!(reply.rcode(), RCODE_NXDOMAIN);
+    assert!(reply.answers.is_empty());
+    assert_eq!(reply.authorities.len(), 1);
+    assert_eq!(reply.authorities[0].name.to_string(), "example.com.");
+    assert_eq!(reply.authorities[0].ttl, 300);
+
+    // hosts.example.com has no records but web.hosts.example.com does
+    for (qname, qtype) in [
+        ("mail.example.com", TYPE_SRV),
+        ("hosts.example.com", TYPE_A),
+    ] {
+        let reply = exchange(server, qname, qtype);
+        assert_eq!(reply.rcode(), RCODE_NOERROR);
+        assert!(reply.answers.is_empty());
+        assert!(matches!(reply.authorities[0].rdata, RData::Soa { .. }));
+    }
+}
+
+#[test]
+fn test_wildcard_answers_for_missing_names() {
+    let server = start_server();
+    let reply = exchange(server, "shop.apps.example.com", TYPE_A);
+    assert_eq!(reply.rcode(), RCODE_NOERROR);
+    assert_eq!(reply.answers.len(), 1);
+    assert_eq!(reply.answers[0].name.to_string(), "shop.apps.example.com.");
+    assert_eq!(reply.answers[0].rdata, RData::A([192, 0, 2, 100].into()));
+
+    // billing.apps exists, so the wildcard doesn't fill in its A record
+    let reply = exchange(server, "billing.apps.example.com", TYPE_A);
+    assert_eq!(reply.rcode(), RCODE_NOERROR);
+    assert!(reply.answers.is_empty());
+    let reply = exchange(server, "x.billing.apps.example.com", TYPE_A);
+    assert_eq!(reply.rcode(), RCODE_NXDOMAIN);
+}
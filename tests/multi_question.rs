@@ -0,0 +1,89 @@
+//! Multi-question queries sent to a running stub server over UDP.
+
+use std::net::{SocketAddr, UdpSocket};
+use std::sync::Arc;
+use std::thread;
+use std::time::Duration;
+
+use dns_starter_rust::server::{serve_udp, Server, ServerConfig};
+use dns_starter_rust::{DnsMessage, DnsQuestion, RData, TYPE_A, TYPE_AAAA, TYPE_MX};
+
+fn start_server() -> SocketAddr {
+    let socket = UdpSocket::bind("127.0.0.1:0").unwrap();
+    let addr = socket.local_addr().unwrap();
+    thread::spawn(move || serve_udp(socket, Arc::new(Server::new(ServerConfig::default()))));
+    addr
+}
+
+fn exchange(server: SocketAddr, questions: &[(&str, u16)]) -> DnsMessage {
+    let query = DnsMessage {
+        questions: questions
+            .iter()
+            .map(|(qname, qtype)| DnsQuestion::new(qname.parse().unwrap(), *qtype))
+            .collect(),
+        ..DnsMessage::query(0x4242, DnsQuestion::new("unused".parse().unwrap(), TYPE_A))
+    };
+    let client = UdpSocket::bind("127.0.0.1:0").unwrap();
+    client
+        .set_read_timeout(Some(Duration::from_secs(5)))
+        .unwrap();
+    client.send_to(&query.to_bytes(), server).unwrap();
+    let mut buf = [0; 4096];
+    let (size, _) = client.recv_from(&mut buf).unwrap();
+    let reply = DnsMessage::from_bytes(&buf[..size]).unwrap();
+    assert_eq!(reply.header.id, 0x4242);
+    assert_eq!(reply.questions, query.questions);
+    reply
+}
+
+#[test]
+fn test_each_question_answered_for_its_own_name() {
+    let reply = exchange(
+        start_server(),
+        &[
+            ("abc.longassdomainname.com", TYPE_A),
+            ("def.longassdomainname.com", TYPE_A),
+            ("ghi.example.org", TYPE_A),
+        ],
+    );
+    assert_eq!(reply.header.answers, 3);
+    let names: Vec<String> = reply
+        .answers
+        .iter()
+        .map(|answer| answer.name.to_string())
+        .collect();
+    assert_eq!(
+        names,
+        [
+            "abc.longassdomainname.com.",
+            "def.longassdomainname.com.",
+            "ghi.example.org."
+        ]
+    );
+}
+
+#[test]
+fn test_each_question_answered_for_its_own_type() {
+    let reply = exchange(
+        start_server(),
+        &[("example.com", TYPE_AAAA), ("example.com", TYPE_A)],
+    );
+    assert_eq!(reply.header.answers, 2);
+    assert_eq!(reply.answers[0].qtype, TYPE_AAAA);
+    assert!(matches!(reply.answers[0].rdata, RData::Aaaa(_)));
+    assert_eq!(reply.answers[1].qtype, TYPE_A);
+    assert!(matches!(reply.answers[1].rdata, RData::A(_)));
+}
+
+#[test]
+fn test_answer_count_matches_records_emitted() {
+    // the stub has nothing for MX, so only the A question gets a record
+    let reply = exchange(
+        start_server(),
+        &[("example.com", TYPE_MX), ("example.com", TYPE_A)],
+    );
+    assert_eq!(reply.header.questions, 2);
+    assert_eq!(reply.header.answers, 1);
+    assert_eq!(reply.answers.len(), 1);
+    assert_eq!(reply.answers[0].qtype, TYPE_A);
+}